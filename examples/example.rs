@@ -1,6 +1,6 @@
 use rand::{self, Rng};
 
-use tdb::{Opts, TDB};
+use tdb::{Opts, SyncMode, TDB};
 
 fn main() {
     let mut tdb = generate_random_db_instance();
@@ -25,7 +25,7 @@ fn main() {
 fn generate_random_db_instance() -> TDB {
     let file_name = generate_random_name();
     let data_dir = format!("./data/{}", file_name);
-    let opts = Opts::new(true, true);
+    let opts = Opts::new(true, SyncMode::Always);
     TDB::open_with_opts(data_dir, opts).unwrap()
 }
 
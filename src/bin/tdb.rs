@@ -0,0 +1,475 @@
+//! Command-line tool for everyday operations on a data directory.
+
+use std::{
+    env,
+    error::Error,
+    fs::{self, File},
+    io::{self, Write},
+    process::ExitCode,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde_json::json;
+use tdb::{
+    CorruptionPolicy, ExportFormat, Opts, RepairReport, SizeHistogram, Stats, SyncMode,
+    VerifyReport, TDB,
+};
+
+const USAGE: &str = "\
+Usage: tdb [OPTIONS] <DATA_DIR> <COMMAND> [ARGS]
+
+Commands:
+  get <KEY>             Print the value of a key, or exit with 1 if it is missing
+  put <KEY> <VALUE>     Store a value
+  delete <KEY>          Delete a key
+  scan [--prefix <P>]   Print the pairs whose keys start with a prefix, in key order
+  keys                  Print every key, in order
+  merge [ID...]         Compact the data files, or only the given ones
+  stats                 Print the number of keys and the live and dead bytes of
+                        every data file
+  export [FILE]         Write every pair to FILE, or to stdout
+  import [FILE]         Load the pairs from FILE, or from stdin
+  verify                Check every entry and key, and exit with 1 on any problem
+  repair                Rewrite damaged data files with what can be read, moving
+                        the rest into the quarantine directory
+
+Options:
+  --hex                 Keys and values are hex, in arguments and in output
+  --key-file <PATH>     Read the key from a file instead
+  --value-file <PATH>   Read the value from a file instead
+  --json                Print JSON instead of text, one object per line, with
+                        keys and values in base64 unless --hex is given
+  --format <FORMAT>     Export format: binary (default) or jsonl
+  -h, --help            Print this help
+
+Only put, delete, merge, import and repair open the store for writing.";
+
+#[derive(Default)]
+struct Args {
+    data_dir: String,
+    command: String,
+    args: Vec<String>,
+    hex: bool,
+    json: bool,
+    key_file: Option<String>,
+    value_file: Option<String>,
+    prefix: Option<String>,
+    format: ExportFormat,
+}
+
+impl Args {
+    fn parse<I: Iterator<Item = String>>(mut iter: I) -> Result<Self, String> {
+        let mut args = Self::default();
+        let mut positional = vec![];
+        while let Some(arg) = iter.next() {
+            let mut value = |name: &str| iter.next().ok_or(format!("{} needs a value", name));
+            match arg.as_str() {
+                "--hex" => args.hex = true,
+                "--json" => args.json = true,
+                "--key-file" => args.key_file = Some(value(&arg)?),
+                "--value-file" => args.value_file = Some(value(&arg)?),
+                "--prefix" => args.prefix = Some(value(&arg)?),
+                "--format" => {
+                    args.format = match value(&arg)?.as_str() {
+                        "binary" => ExportFormat::Binary,
+                        "jsonl" => ExportFormat::JsonLines,
+                        format => return Err(format!("unknown format {}", format)),
+                    }
+                }
+                "-h" | "--help" => return Err(String::new()),
+                _ if arg.starts_with('-') && arg.len() > 1 => {
+                    return Err(format!("unknown option {}", arg))
+                }
+                _ => positional.push(arg),
+            }
+        }
+        let mut positional = positional.into_iter();
+        args.data_dir = positional.next().ok_or("missing data directory")?;
+        args.command = positional.next().ok_or("missing command")?;
+        args.args = positional.collect();
+
+        let key_args = (args.key_file.is_none()) as usize;
+        let value_args = (args.value_file.is_none()) as usize;
+        let expected = match args.command.as_str() {
+            "get" | "delete" => key_args..=key_args,
+            "put" => key_args + value_args..=key_args + value_args,
+            "scan" | "keys" | "stats" | "verify" | "repair" => 0..=0,
+            "merge" => 0..=usize::MAX,
+            "export" | "import" => 0..=1,
+            command => return Err(format!("unknown command {}", command)),
+        };
+        if !expected.contains(&args.args.len()) {
+            return Err(format!("wrong number of arguments for {}", args.command));
+        }
+        Ok(args)
+    }
+
+    fn is_writing(&self) -> bool {
+        matches!(
+            self.command.as_str(),
+            "put" | "delete" | "merge" | "import" | "repair"
+        )
+    }
+
+    fn key(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        match &self.key_file {
+            Some(path) => Ok(fs::read(path)?),
+            None => self.decode(&self.args[0]),
+        }
+    }
+
+    fn value(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        match &self.value_file {
+            Some(path) => Ok(fs::read(path)?),
+            None => self.decode(self.args.last().unwrap()),
+        }
+    }
+
+    fn decode(&self, arg: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        if !self.hex {
+            return Ok(arg.as_bytes().to_vec());
+        }
+        if !arg.len().is_multiple_of(2) {
+            return Err(format!("odd number of hex digits in {}", arg).into());
+        }
+        (0..arg.len())
+            .step_by(2)
+            .map(|i| {
+                u8::from_str_radix(arg.get(i..i + 2).unwrap_or("?"), 16)
+                    .map_err(|_| format!("invalid hex {}", arg).into())
+            })
+            .collect()
+    }
+
+    /// Hex with `--hex`, and otherwise base64 in JSON. In text, bytes that
+    /// are not UTF-8 are escaped as in `\xff`, and backslashes are doubled.
+    fn encode(&self, bytes: &[u8]) -> String {
+        if self.hex {
+            return bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        }
+        if self.json {
+            return STANDARD.encode(bytes);
+        }
+        let mut text = String::new();
+        for chunk in bytes.utf8_chunks() {
+            text.push_str(&chunk.valid().replace('\\', "\\\\"));
+            for b in chunk.invalid() {
+                text.push_str(&format!("\\x{:02x}", b));
+            }
+        }
+        text
+    }
+}
+
+fn main() -> ExitCode {
+    let args = match Args::parse(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(msg) if msg.is_empty() => {
+            println!("{}", USAGE);
+            return ExitCode::SUCCESS;
+        }
+        Err(msg) => {
+            eprintln!("tdb: {}\n\n{}", msg, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+    match run(&args) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("tdb: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(args: &Args) -> Result<ExitCode, Box<dyn Error>> {
+    // The store may not open before it is repaired.
+    if args.command == "repair" {
+        let report = TDB::repair(&args.data_dir)?;
+        print_repair_report(args, &report, &mut io::stdout().lock())?;
+        return Ok(ExitCode::SUCCESS);
+    }
+    // Readers follow the writer rather than lock it out, so that a store in
+    // use can be looked at.
+    let mut opts = Opts::new(args.is_writing(), SyncMode::OnClose);
+    opts.follow(true);
+    if args.command == "verify" {
+        // Damage is for verify to report, not to refuse opening over.
+        opts.corruption_policy(CorruptionPolicy::Skip);
+    }
+    let mut db = TDB::open_with_opts(&args.data_dir, opts)?;
+    let mut out = io::stdout().lock();
+
+    match args.command.as_str() {
+        "get" => {
+            let key = args.key()?;
+            let value = db.get(&key)?;
+            if args.json {
+                let value = value.as_deref().map(|value| args.encode(value));
+                writeln!(out, "{}", json!({"key": args.encode(&key), "value": value}))?;
+            } else if let Some(value) = &value {
+                writeln!(out, "{}", args.encode(value))?;
+            }
+            if value.is_none() {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        "put" => db.put(&args.key()?, &args.value()?)?,
+        "delete" => db.delete(&args.key()?)?,
+        "scan" => {
+            let prefix = args.decode(args.prefix.as_deref().unwrap_or_default())?;
+            for pair in db.scan_prefix(&prefix) {
+                let (key, value) = pair?;
+                let (key, value) = (args.encode(&key), args.encode(&value));
+                if args.json {
+                    writeln!(out, "{}", json!({"key": key, "value": value}))?;
+                } else {
+                    writeln!(out, "{}\t{}", key, value)?;
+                }
+            }
+        }
+        "keys" => {
+            for key in db.keys() {
+                if args.json {
+                    writeln!(out, "{}", json!(args.encode(&key)))?;
+                } else {
+                    writeln!(out, "{}", args.encode(&key))?;
+                }
+            }
+        }
+        "merge" if args.args.is_empty() => db.merge()?,
+        "merge" => {
+            let file_ids = args
+                .args
+                .iter()
+                .map(|id| id.parse().map_err(|_| format!("invalid file id {}", id)))
+                .collect::<Result<Vec<usize>, _>>()?;
+            db.merge_files(&file_ids)?
+        }
+        "stats" => print_stats(args, &db.stats(), &mut out)?,
+        "export" => {
+            let count = match args.args.first() {
+                Some(path) => db.export(File::create(path)?, args.format)?,
+                None => db.export(&mut out, args.format)?,
+            };
+            eprintln!("exported {} pairs", count);
+        }
+        "import" => {
+            let count = match args.args.first() {
+                Some(path) => db.import(File::open(path)?, args.format)?,
+                None => db.import(io::stdin().lock(), args.format)?,
+            };
+            eprintln!("imported {} pairs", count);
+        }
+        "verify" => {
+            let report = db.verify()?;
+            print_verify_report(args, &report, &mut out)?;
+            if !report.is_ok() {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        _ => unreachable!(),
+    }
+    db.close()?;
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn print_verify_report<W: Write>(
+    args: &Args,
+    report: &VerifyReport,
+    out: &mut W,
+) -> io::Result<()> {
+    if args.json {
+        let bad_entries: Vec<_> = report
+            .bad_entries
+            .iter()
+            .map(|bad| {
+                json!({
+                    "file_id": bad.file_id,
+                    "offset": bad.offset,
+                    "len": bad.len,
+                    "key": bad.key.as_deref().map(|key| args.encode(key)),
+                    "reason": bad.reason,
+                })
+            })
+            .collect();
+        let bad_keys: Vec<_> = report
+            .bad_keys
+            .iter()
+            .map(|bad| {
+                json!({
+                    "key": args.encode(&bad.key),
+                    "file_id": bad.file_id,
+                    "value_pos": bad.value_pos,
+                    "reason": bad.reason,
+                })
+            })
+            .collect();
+        let report = json!({
+            "ok": report.is_ok(),
+            "files": report.files,
+            "entries": report.entries,
+            "bad_entries": bad_entries,
+            "bad_keys": bad_keys,
+        });
+        return writeln!(out, "{}", report);
+    }
+
+    for bad in &report.bad_entries {
+        write!(
+            out,
+            "bad entry in file {} at offset {} ({} bytes): {}",
+            bad.file_id, bad.offset, bad.len, bad.reason
+        )?;
+        match &bad.key {
+            Some(key) => writeln!(out, ", key {}", args.encode(key))?,
+            None => writeln!(out)?,
+        }
+    }
+    for bad in &report.bad_keys {
+        writeln!(
+            out,
+            "bad key {}: {} (file {}, value at offset {})",
+            args.encode(&bad.key),
+            bad.reason,
+            bad.file_id,
+            bad.value_pos
+        )?;
+    }
+    writeln!(
+        out,
+        "checked {} entries in {} files: {} bad entries, {} bad keys",
+        report.entries,
+        report.files,
+        report.bad_entries.len(),
+        report.bad_keys.len()
+    )
+}
+
+fn print_repair_report<W: Write>(
+    args: &Args,
+    report: &RepairReport,
+    out: &mut W,
+) -> io::Result<()> {
+    if args.json {
+        let quarantined: Vec<_> = report
+            .quarantined
+            .iter()
+            .map(|span| {
+                json!({
+                    "file_id": span.file_id,
+                    "offset": span.offset,
+                    "len": span.len,
+                    "path": span.path,
+                })
+            })
+            .collect();
+        let keys_at_risk: Vec<_> = report
+            .keys_at_risk
+            .iter()
+            .map(|key| args.encode(key))
+            .collect();
+        let report = json!({
+            "files": report.files,
+            "entries": report.entries,
+            "quarantined": quarantined,
+            "keys_at_risk": keys_at_risk,
+            "report_path": report.report_path,
+        });
+        return writeln!(out, "{}", report);
+    }
+
+    for span in &report.quarantined {
+        writeln!(
+            out,
+            "quarantined file {} at offset {} ({} bytes) to {}",
+            span.file_id,
+            span.offset,
+            span.len,
+            span.path.display()
+        )?;
+    }
+    for key in &report.keys_at_risk {
+        writeln!(out, "key at risk: {}", args.encode(key))?;
+    }
+    writeln!(
+        out,
+        "kept {} entries in {} files: {} spans quarantined, {} keys at risk",
+        report.entries,
+        report.files,
+        report.quarantined.len(),
+        report.keys_at_risk.len()
+    )?;
+    if let Some(report_path) = &report.report_path {
+        writeln!(out, "report written to {}", report_path.display())?;
+    }
+    Ok(())
+}
+
+fn print_stats<W: Write>(args: &Args, stats: &Stats, out: &mut W) -> io::Result<()> {
+    // Empty buckets are left out.
+    let buckets = |histogram: &SizeHistogram| {
+        histogram
+            .counts
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(i, count)| (SizeHistogram::bucket_range(i), *count))
+            .collect::<Vec<_>>()
+    };
+    if args.json {
+        let files: Vec<_> = stats
+            .files
+            .iter()
+            .map(|file| {
+                json!({
+                    "file_id": file.file_id,
+                    "size": file.size,
+                    "live_bytes": file.live_bytes,
+                    "dead_bytes": file.dead_bytes,
+                    "tombstones": file.tombstones,
+                })
+            })
+            .collect();
+        let histogram = |histogram| {
+            buckets(histogram)
+                .into_iter()
+                .map(|(range, count)| {
+                    json!({"min": range.start(), "max": range.end(), "count": count})
+                })
+                .collect::<Vec<_>>()
+        };
+        let stats = json!({
+            "keys": stats.keys,
+            "total_bytes": stats.total_bytes,
+            "live_bytes": stats.live_bytes,
+            "dead_bytes": stats.dead_bytes,
+            "keydir_memory": stats.keydir_memory,
+            "files": files,
+            "key_sizes": histogram(&stats.key_sizes),
+            "value_sizes": histogram(&stats.value_sizes),
+        });
+        return writeln!(out, "{}", stats);
+    }
+
+    writeln!(out, "keys: {}", stats.keys)?;
+    writeln!(out, "total bytes: {}", stats.total_bytes)?;
+    writeln!(out, "live bytes: {}", stats.live_bytes)?;
+    writeln!(out, "dead bytes: {}", stats.dead_bytes)?;
+    writeln!(out, "keydir memory: about {} bytes", stats.keydir_memory)?;
+    for file in &stats.files {
+        writeln!(
+            out,
+            "file {}: {} bytes, {} live, {} dead, {} tombstones",
+            file.file_id, file.size, file.live_bytes, file.dead_bytes, file.tombstones
+        )?;
+    }
+    for (name, histogram) in [("key", &stats.key_sizes), ("value", &stats.value_sizes)] {
+        writeln!(out, "{} sizes:", name)?;
+        for (range, count) in buckets(histogram) {
+            writeln!(out, "  {}-{}: {}", range.start(), range.end(), count)?;
+        }
+    }
+    Ok(())
+}
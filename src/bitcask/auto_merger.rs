@@ -0,0 +1,189 @@
+//! Background thread behind [`Opts::auto_merge_interval`](super::opts::Opts).
+
+use std::{
+    collections::BTreeSet,
+    sync::{Arc, Condvar, Mutex, RwLock, Weak},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crate::error::DBError;
+
+use super::{now_micros, opts::Opts, storage::Storage, FileId};
+
+const DAY_MICROS: u64 = 24 * 60 * 60 * 1_000_000;
+
+#[derive(Default)]
+struct State {
+    /// Set to make the thread exit, giving up on a merge in progress.
+    stop: bool,
+    /// The last failed merge, reported by the next call to
+    /// [`AutoMerger::check`].
+    error: Option<DBError>,
+}
+
+/// When a background merge is due, as set in [`Opts`].
+pub(super) struct Triggers {
+    /// Whether the merge thresholds pick files.
+    thresholds: bool,
+    dead_bytes: Option<u64>,
+    /// Start and end of the daily window, in microseconds since midnight.
+    window: Option<(u64, u64)>,
+    /// When the last merge in the window started.
+    last_window_merge: Option<u64>,
+}
+
+impl Triggers {
+    pub(super) fn new(opts: &Opts) -> Self {
+        Self {
+            thresholds: opts.get_merge_dead_ratio().is_some()
+                || opts.get_merge_dead_bytes().is_some(),
+            dead_bytes: opts.get_auto_merge_dead_bytes(),
+            window: opts.get_auto_merge_window().map(|(start, end)| {
+                let micros = |time: Duration| time.as_micros() as u64 % DAY_MICROS;
+                (micros(start), micros(end))
+            }),
+            last_window_merge: None,
+        }
+    }
+
+    /// Returns the files to merge now, if any. The files over a merge
+    /// threshold come first, and every file with dead bytes is merged when
+    /// there are too many dead bytes in all or the window has opened.
+    fn pick(&mut self, storage: &Storage) -> BTreeSet<FileId> {
+        if self.thresholds {
+            let file_ids = storage.files_to_merge();
+            if !file_ids.is_empty() {
+                return file_ids;
+            }
+        }
+        let stats = storage.stats();
+        let too_many = self
+            .dead_bytes
+            .is_some_and(|dead_bytes| stats.dead_bytes >= dead_bytes);
+        if !too_many && !self.window_opened() {
+            return BTreeSet::new();
+        }
+        stats
+            .files
+            .iter()
+            .filter(|f| f.dead_bytes > 0)
+            .map(|f| f.file_id)
+            .collect()
+    }
+
+    /// Whether the window is open and has not seen a merge since it opened.
+    fn window_opened(&mut self) -> bool {
+        let Some((start, end)) = self.window else {
+            return false;
+        };
+        let now = now_micros();
+        let since_start = (now % DAY_MICROS + DAY_MICROS - start) % DAY_MICROS;
+        let len = (end + DAY_MICROS - start) % DAY_MICROS;
+        let opened = now - since_start;
+        if since_start >= len || self.last_window_merge.is_some_and(|last| last >= opened) {
+            return false;
+        }
+        self.last_window_merge = Some(now);
+        true
+    }
+}
+
+/// Checks every `interval` whether a merge is due according to its
+/// [`Triggers`], and runs it. The live values are copied without holding
+/// the lock on the storage, which is only taken to start the merge and to
+/// commit it.
+pub(super) struct AutoMerger {
+    shared: Arc<(Mutex<State>, Condvar)>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl AutoMerger {
+    pub(super) fn spawn(
+        storage: Weak<RwLock<Storage>>,
+        interval: Duration,
+        mut triggers: Triggers,
+        rate: Option<u64>,
+    ) -> Self {
+        let shared = Arc::new((Mutex::new(State::default()), Condvar::new()));
+        let thread_shared = shared.clone();
+        let handle = thread::spawn(move || {
+            let (state, condvar) = &*thread_shared;
+            loop {
+                let guard = state.lock().unwrap();
+                let (guard, _) = condvar
+                    .wait_timeout_while(guard, interval, |s| !s.stop)
+                    .unwrap();
+                if guard.stop {
+                    break;
+                }
+                drop(guard);
+
+                let Some(storage) = storage.upgrade() else {
+                    break;
+                };
+                let file_ids = triggers.pick(&storage.read().unwrap());
+                if file_ids.is_empty() {
+                    continue;
+                }
+                let res = Self::merge(&storage, file_ids, rate, || state.lock().unwrap().stop);
+                if let Err(e) = res {
+                    state.lock().unwrap().error = Some(e);
+                }
+            }
+        });
+
+        Self {
+            shared,
+            handle: Some(handle),
+        }
+    }
+
+    /// Returns the error of a background merge that failed since the last
+    /// call.
+    pub(super) fn check(&self) -> Result<(), DBError> {
+        match self.shared.0.lock().unwrap().error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Stops the thread, which gives up on a merge in progress, and waits
+    /// for it to exit.
+    pub(super) fn stop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            let (state, condvar) = &*self.shared;
+            state.lock().unwrap().stop = true;
+            condvar.notify_one();
+            let _ = handle.join();
+        }
+    }
+
+    /// Merges `file_ids`, holding the write lock on `storage` only to start
+    /// and to finish, unless `stop` says to give up first.
+    fn merge<F: Fn() -> bool>(
+        storage: &RwLock<Storage>,
+        file_ids: BTreeSet<FileId>,
+        rate: Option<u64>,
+        stop: F,
+    ) -> Result<(), DBError> {
+        let mut merger = storage.write().unwrap().start_merge(file_ids)?;
+        if let Some(rate) = rate {
+            merger.limit_rate(rate);
+        }
+        match merger.write(stop) {
+            Ok(true) => storage.write().unwrap().finish_merge(merger),
+            Ok(false) => storage.write().unwrap().abort_merge(merger),
+            Err(e) => {
+                storage.write().unwrap().abort_merge(merger)?;
+                Err(e)
+            }
+        }
+    }
+}
+
+impl Drop for AutoMerger {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
@@ -0,0 +1,335 @@
+//! Incremental backups of a database into a backup repository.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    io::{self, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use crc::{Crc, CRC_32_CKSUM};
+
+use crate::error::DBError;
+
+use super::{log::sync_dir, now_micros, BitCask, FileId, SizeType};
+
+/// A backup recorded in a [`BackupRepository`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupInfo {
+    /// Backups are numbered from 1 up in the order they were taken.
+    pub id: u64,
+    /// When the backup was taken, in microseconds since the Unix epoch.
+    pub timestamp: u64,
+    /// Number of data files making up the backup.
+    pub files: usize,
+    /// Total size of those data files in bytes.
+    pub size: u64,
+}
+
+/// A data file as stored in the repository.
+struct BackupFile {
+    file_id: FileId,
+    file_sz: SizeType,
+    /// CRC-32 of the contents.
+    checksum: u32,
+    has_hint: bool,
+}
+
+impl BackupFile {
+    /// A file can be rewritten under the same id, e.g. by a repair, so only
+    /// the id, the size and the checksum together identify its contents.
+    fn name(&self) -> String {
+        format!("{}-{}-{:08x}", self.file_id, self.file_sz, self.checksum)
+    }
+}
+
+/// A directory holding backups of one database. Each backup is a manifest
+/// listing data files, and every data file is stored once, however many
+/// backups use it, so a backup only copies the files that are new since the
+/// previous ones. The files are copied, so the repository never shares them
+/// with the database.
+pub struct BackupRepository {
+    dir: PathBuf,
+}
+
+impl BackupRepository {
+    const FILES_DIR: &'static str = "files";
+    const BACKUPS_DIR: &'static str = "backups";
+    const DATA_EXTENSION: &'static str = "tdb";
+    const HINT_EXTENSION: &'static str = "hint";
+    const TMP_EXTENSION: &'static str = "tmp";
+    const MAGIC: [u8; 4] = *b"TDBB";
+    const VERSION: u32 = 1;
+    const CHECKSUM_SIZE: usize = 4;
+    const CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_CKSUM);
+
+    /// Opens the repository in `dir`, creating it if needed.
+    pub fn open<T: Into<PathBuf>>(dir: T) -> Result<Self, DBError> {
+        let dir = dir.into();
+        fs::create_dir_all(dir.join(Self::FILES_DIR))?;
+        fs::create_dir_all(dir.join(Self::BACKUPS_DIR))?;
+        Ok(Self { dir })
+    }
+
+    /// Backs `db` up as it is now. Like
+    /// [`BitCask::checkpoint`](super::BitCask::checkpoint), this seals the
+    /// active file and does not stop writes for longer than that. A data file
+    /// is only read if the repository does not hold it yet: the checksum
+    /// naming it is the one recorded when it was sealed, or else the one of
+    /// the stored file with the same id and size.
+    pub fn create_backup(&self, db: &BitCask) -> Result<BackupInfo, DBError> {
+        let checkpoint = db.storage.write().unwrap().checkpoint()?;
+        let files_dir = self.dir.join(Self::FILES_DIR);
+        let stored = self.stored_checksums()?;
+        let mut files = vec![];
+        for (file_id, file_sz) in checkpoint.file_sizes()? {
+            let checksum = match checkpoint.sealed_checksum(file_id) {
+                Some(checksum) => checksum,
+                None => match stored.get(&(file_id, file_sz)) {
+                    Some(checksum) => *checksum,
+                    None => checkpoint.checksum(file_id, file_sz)?,
+                },
+            };
+            let mut backup_file = BackupFile {
+                file_id,
+                file_sz,
+                checksum,
+                has_hint: false,
+            };
+            let path = self.data_path(&backup_file);
+            let hint_path = path.with_extension(Self::HINT_EXTENSION);
+            if path.exists() {
+                backup_file.has_hint = hint_path.exists();
+            } else {
+                // The data file is renamed into place last, so that a backup
+                // cut short leaves no file that looks complete.
+                let tmp_path = path.with_extension(Self::TMP_EXTENSION);
+                remove_if_exists(&tmp_path)?;
+                backup_file.has_hint =
+                    checkpoint.write_file(file_id, &tmp_path, &hint_path, false)?;
+                fs::rename(tmp_path, path)?;
+            }
+            files.push(backup_file);
+        }
+        sync_dir(&files_dir)?;
+
+        let id = self.backup_ids()?.last().map_or(1, |id| id + 1);
+        let timestamp = now_micros();
+        self.write_manifest(id, timestamp, &files)?;
+        Ok(Self::info(id, timestamp, &files))
+    }
+
+    /// Returns the backups, oldest first.
+    pub fn list_backups(&self) -> Result<Vec<BackupInfo>, DBError> {
+        self.backup_ids()?
+            .into_iter()
+            .map(|id| {
+                let (timestamp, files) = self.read_manifest(id)?;
+                Ok(Self::info(id, timestamp, &files))
+            })
+            .collect()
+    }
+
+    /// Rebuilds the database as of backup `id` in `dest_dir`, which must be
+    /// empty or not exist. The files are copied, so the database can be
+    /// used right away without touching the repository.
+    pub fn restore<T: Into<PathBuf>>(&self, id: u64, dest_dir: T) -> Result<(), DBError> {
+        let dest_dir = dest_dir.into();
+        let (_, files) = self.read_manifest(id)?;
+        fs::create_dir_all(&dest_dir)?;
+        if fs::read_dir(&dest_dir)?.next().is_some() {
+            return Err(io::Error::new(
+                ErrorKind::AlreadyExists,
+                format!("{} is not empty", dest_dir.display()),
+            )
+            .into());
+        }
+        for backup_file in &files {
+            let path = self.data_path(backup_file);
+            let mut dest_path = dest_dir.join(backup_file.file_id.to_string());
+            dest_path.set_extension(Self::DATA_EXTENSION);
+            copy_synced(&path, &dest_path)?;
+            if backup_file.has_hint {
+                copy_synced(
+                    &path.with_extension(Self::HINT_EXTENSION),
+                    &dest_path.with_extension(Self::HINT_EXTENSION),
+                )?;
+            }
+        }
+        sync_dir(&dest_dir)
+    }
+
+    /// Deletes all but the `keep` newest backups, then the files no
+    /// remaining backup uses.
+    pub fn prune(&self, keep: usize) -> Result<(), DBError> {
+        let ids = self.backup_ids()?;
+        let backups_dir = self.dir.join(Self::BACKUPS_DIR);
+        for id in &ids[..ids.len().saturating_sub(keep)] {
+            fs::remove_file(backups_dir.join(id.to_string()))?;
+        }
+        sync_dir(&backups_dir)?;
+
+        let mut used = BTreeSet::new();
+        for id in self.backup_ids()? {
+            for backup_file in self.read_manifest(id)?.1 {
+                used.insert(backup_file.name());
+            }
+        }
+        let files_dir = self.dir.join(Self::FILES_DIR);
+        for path in fs::read_dir(&files_dir)? {
+            let path = path?.path();
+            let name = path.file_stem().and_then(|name| name.to_str());
+            if !name.is_some_and(|name| used.contains(name)) {
+                fs::remove_file(path)?;
+            }
+        }
+        sync_dir(&files_dir)
+    }
+
+    fn info(id: u64, timestamp: u64, files: &[BackupFile]) -> BackupInfo {
+        BackupInfo {
+            id,
+            timestamp,
+            files: files.len(),
+            size: files.iter().map(|f| f.file_sz).sum(),
+        }
+    }
+
+    fn data_path(&self, backup_file: &BackupFile) -> PathBuf {
+        let mut path = self.dir.join(Self::FILES_DIR).join(backup_file.name());
+        path.set_extension(Self::DATA_EXTENSION);
+        path
+    }
+
+    /// Returns the checksums of the stored data files by id and size.
+    fn stored_checksums(&self) -> Result<BTreeMap<(FileId, SizeType), u32>, DBError> {
+        let mut checksums = BTreeMap::new();
+        for path in fs::read_dir(self.dir.join(Self::FILES_DIR))? {
+            let path = path?.path();
+            if path.extension() != Some(Self::DATA_EXTENSION.as_ref()) {
+                continue;
+            }
+            let Some(name) = path.file_stem().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            let mut parts = name.split('-');
+            let file_id = parts.next().and_then(|part| part.parse().ok());
+            let file_sz = parts.next().and_then(|part| part.parse().ok());
+            let checksum = parts
+                .next()
+                .and_then(|part| u32::from_str_radix(part, 16).ok());
+            if let (Some(file_id), Some(file_sz), Some(checksum)) = (file_id, file_sz, checksum) {
+                checksums.insert((file_id, file_sz), checksum);
+            }
+        }
+        Ok(checksums)
+    }
+
+    /// Ids of the recorded backups, in increasing order.
+    fn backup_ids(&self) -> Result<Vec<u64>, DBError> {
+        let mut ids = vec![];
+        for path in fs::read_dir(self.dir.join(Self::BACKUPS_DIR))? {
+            if let Some(id) = path?.file_name().to_str().and_then(|s| s.parse().ok()) {
+                ids.push(id);
+            }
+        }
+        ids.sort_unstable();
+        Ok(ids)
+    }
+
+    /// Atomically writes the manifest of backup `id`.
+    fn write_manifest(&self, id: u64, timestamp: u64, files: &[BackupFile]) -> Result<(), DBError> {
+        let mut buf = vec![];
+        buf.write_all(&Self::MAGIC)?;
+        buf.write_all(&Self::VERSION.to_be_bytes())?;
+        buf.write_all(&timestamp.to_be_bytes())?;
+        buf.write_all(&(files.len() as u64).to_be_bytes())?;
+        for backup_file in files {
+            buf.write_all(&(backup_file.file_id as u64).to_be_bytes())?;
+            buf.write_all(&backup_file.file_sz.to_be_bytes())?;
+            buf.write_all(&backup_file.checksum.to_be_bytes())?;
+            buf.write_all(&[backup_file.has_hint as u8])?;
+        }
+        let checksum = Self::CRC32.checksum(&buf);
+        buf.write_all(&checksum.to_be_bytes())?;
+
+        let backups_dir = self.dir.join(Self::BACKUPS_DIR);
+        let path = backups_dir.join(id.to_string());
+        let tmp_path = path.with_extension(Self::TMP_EXTENSION);
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        fs::rename(tmp_path, path)?;
+        sync_dir(&backups_dir)
+    }
+
+    fn read_manifest(&self, id: u64) -> Result<(u64, Vec<BackupFile>), DBError> {
+        let buf = fs::read(self.dir.join(Self::BACKUPS_DIR).join(id.to_string()))?;
+        let invalid = || DBError::DataError(format!("invalid manifest of backup {}", id));
+        if buf.len() < Self::CHECKSUM_SIZE {
+            return Err(invalid());
+        }
+        let (mut body, checksum) = buf.split_at(buf.len() - Self::CHECKSUM_SIZE);
+        if Self::CRC32.checksum(body).to_be_bytes() != checksum {
+            return Err(invalid());
+        }
+
+        let mut magic = [0_u8; 4];
+        body.read_exact(&mut magic)?;
+        let version = read_u32(&mut body)?;
+        if magic != Self::MAGIC {
+            return Err(invalid());
+        }
+        if version == 0 || version > Self::VERSION {
+            return Err(DBError::VersionError(format!(
+                "backup {} has format version {}, but only versions up to {} are supported",
+                id,
+                version,
+                Self::VERSION
+            )));
+        }
+        let timestamp = read_u64(&mut body)?;
+        let files = (0..read_u64(&mut body)?)
+            .map(|_| {
+                let file_id = read_u64(&mut body)? as FileId;
+                let file_sz = read_u64(&mut body)?;
+                let checksum = read_u32(&mut body)?;
+                let mut has_hint = [0_u8];
+                body.read_exact(&mut has_hint)?;
+                Ok(BackupFile {
+                    file_id,
+                    file_sz,
+                    checksum,
+                    has_hint: has_hint[0] != 0,
+                })
+            })
+            .collect::<Result<_, DBError>>()?;
+        Ok((timestamp, files))
+    }
+}
+
+fn read_u32<T: Read>(buf: &mut T) -> Result<u32, DBError> {
+    let mut bytes = [0_u8; 4];
+    buf.read_exact(&mut bytes)?;
+    Ok(u32::from_be_bytes(bytes))
+}
+
+fn read_u64<T: Read>(buf: &mut T) -> Result<u64, DBError> {
+    let mut bytes = [0_u8; 8];
+    buf.read_exact(&mut bytes)?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+fn copy_synced(from: &Path, to: &Path) -> Result<(), DBError> {
+    fs::copy(from, to)?;
+    fs::File::open(to)?.sync_all()?;
+    Ok(())
+}
+
+fn remove_if_exists(path: &Path) -> Result<(), DBError> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
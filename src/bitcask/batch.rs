@@ -0,0 +1,56 @@
+//! Groups of writes that take effect together.
+
+use super::{Key, Value};
+
+pub(super) enum BatchOp {
+    /// A put, with the time it expires at in microseconds since the Unix
+    /// epoch, if any.
+    Put(Key, Value, Option<u64>),
+    Delete(Key),
+}
+
+/// A list of puts and deletes applied by
+/// [`BitCask::write_batch`](super::BitCask::write_batch). Either all of them
+/// take effect or none of them does, even across a crash.
+#[derive(Default)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+impl WriteBatch {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[inline]
+    pub fn put(&mut self, key: &Key, value: &Value) {
+        self.ops
+            .push(BatchOp::Put(key.clone(), value.clone(), None));
+    }
+
+    #[inline]
+    pub fn delete(&mut self, key: &Key) {
+        self.ops.push(BatchOp::Delete(key.clone()));
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    #[inline]
+    pub fn clear(&mut self) {
+        self.ops.clear();
+    }
+
+    #[inline]
+    pub(super) fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+}
@@ -0,0 +1,340 @@
+//! A portable format to move data between databases and into other tools.
+
+use std::io::{self, BufRead, BufReader, BufWriter, ErrorKind, Read, Write};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use crc::{Crc, Digest, CRC_32_CKSUM};
+use serde_json::{json, Value as Json};
+
+use crate::error::DBError;
+
+use super::{snapshot::Snapshot, Key, Value};
+
+/// Layout of an export stream, written by `Bitcask::export` and read by
+/// `Bitcask::import`.
+///
+/// Both flavours hold the live key/value pairs in key order, with the time
+/// each one expires at if it has a TTL, and end with the number of pairs and
+/// a CRC-32/CKSUM checksum of everything before it. Timestamps and deleted
+/// keys are not part of it.
+///
+/// # Binary, version 1
+///
+/// All integers are big-endian.
+///
+/// ```text
+/// header:  "TDBX" | version: u32
+/// pair:    1: u8 | key length: u64 | key | value length: u64 | value
+///          | expiry: u64 (microseconds since the Unix epoch, 0 for none)
+/// trailer: 0: u8 | number of pairs: u64 | checksum: u32
+/// ```
+///
+/// The checksum covers the header, the pairs and the trailer up to the number
+/// of pairs.
+///
+/// # JSON Lines, version 1
+///
+/// One JSON object per line, with keys and values in standard base64 with
+/// padding:
+///
+/// ```text
+/// {"format":"tdb","version":1}
+/// {"key":"AQI=","value":"AwQ=","expiry":1718000000000000}
+/// {"count":1,"checksum":123456789}
+/// ```
+///
+/// `expiry` is left out for pairs that never expire. The checksum covers
+/// every line before the last one, newlines included.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExportFormat {
+    /// Length-prefixed binary.
+    #[default]
+    Binary,
+    /// JSON Lines with base64 keys and values.
+    JsonLines,
+}
+
+const MAGIC: [u8; 4] = *b"TDBX";
+const VERSION: u32 = 1;
+const JSON_FORMAT: &str = "tdb";
+const PAIR_TAG: u8 = 1;
+const END_TAG: u8 = 0;
+static CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_CKSUM);
+
+/// Writes every live pair of `snapshot` to `writer`, walking its keydir in
+/// key order. Returns the number of pairs.
+pub(super) fn export<W: Write>(
+    snapshot: &Snapshot,
+    writer: W,
+    format: ExportFormat,
+) -> Result<u64, DBError> {
+    let mut writer = ChecksumWriter {
+        inner: BufWriter::new(writer),
+        digest: CRC32.digest(),
+    };
+    match format {
+        ExportFormat::Binary => {
+            writer.write_all(&MAGIC)?;
+            writer.write_all(&VERSION.to_be_bytes())?;
+        }
+        ExportFormat::JsonLines => {
+            writeln!(
+                writer,
+                "{}",
+                json!({"format": JSON_FORMAT, "version": VERSION})
+            )?;
+        }
+    }
+
+    let mut count = 0_u64;
+    let pairs = snapshot
+        .get_keydir()
+        .iter()
+        .filter(|(_, entry)| !entry.is_expired());
+    for (key, entry) in pairs {
+        let value = snapshot.read_value(entry)?;
+        match format {
+            ExportFormat::Binary => {
+                writer.write_all(&[PAIR_TAG])?;
+                writer.write_all(&(key.len() as u64).to_be_bytes())?;
+                writer.write_all(key)?;
+                writer.write_all(&(value.len() as u64).to_be_bytes())?;
+                writer.write_all(&value)?;
+                writer.write_all(&entry.expiry.unwrap_or(0).to_be_bytes())?;
+            }
+            ExportFormat::JsonLines => {
+                let mut line = json!({
+                    "key": STANDARD.encode(key),
+                    "value": STANDARD.encode(&value),
+                });
+                if let Some(expiry) = entry.expiry {
+                    line["expiry"] = json!(expiry);
+                }
+                writeln!(writer, "{}", line)?;
+            }
+        }
+        count += 1;
+    }
+
+    match format {
+        ExportFormat::Binary => {
+            writer.write_all(&[END_TAG])?;
+            writer.write_all(&count.to_be_bytes())?;
+            let checksum = writer.digest.finalize();
+            writer.inner.write_all(&checksum.to_be_bytes())?;
+        }
+        ExportFormat::JsonLines => {
+            let checksum = writer.digest.finalize();
+            let trailer = json!({"count": count, "checksum": checksum});
+            writeln!(writer.inner, "{}", trailer)?;
+        }
+    }
+    writer.inner.flush()?;
+
+    Ok(count)
+}
+
+/// Reads an export stream from `reader` and hands every pair to `f`, along
+/// with the time it expires at, if any. Returns the number of pairs once the
+/// trailer has been checked. Pairs are handed over before that, so `f` must
+/// not make them visible until then.
+pub(super) fn import<R, F>(reader: R, format: ExportFormat, mut f: F) -> Result<u64, DBError>
+where
+    R: Read,
+    F: FnMut(Key, Value, Option<u64>) -> Result<(), DBError>,
+{
+    let mut reader = ChecksumReader {
+        inner: BufReader::new(reader),
+        digest: CRC32.digest(),
+    };
+    let (count, checksum, expected_count, expected_checksum) = match format {
+        ExportFormat::Binary => import_binary(&mut reader, &mut f)?,
+        ExportFormat::JsonLines => import_json_lines(&mut reader, &mut f)?,
+    };
+    if count != expected_count {
+        return Err(DBError::DataError(format!(
+            "export stream has {} pairs, but its trailer says {}",
+            count, expected_count
+        )));
+    }
+    if checksum != expected_checksum {
+        return Err(DBError::DataError(
+            "invalid export stream checksum".to_string(),
+        ));
+    }
+
+    Ok(count)
+}
+
+/// Returns the number of pairs read and the checksum of the stream, followed
+/// by what the trailer says they are.
+fn import_binary<R: BufRead, F>(
+    reader: &mut ChecksumReader<R>,
+    f: &mut F,
+) -> Result<(u64, u32, u64, u32), DBError>
+where
+    F: FnMut(Key, Value, Option<u64>) -> Result<(), DBError>,
+{
+    let mut header = [0_u8; 8];
+    reader.read_exact(&mut header)?;
+    let (magic, version) = header.split_at(MAGIC.len());
+    if magic != MAGIC {
+        return Err(DBError::DataError("not a binary export stream".to_string()));
+    }
+    check_version(u32::from_be_bytes(version.try_into().unwrap()) as u64)?;
+
+    let mut count = 0;
+    loop {
+        let mut tag = [0_u8];
+        reader.read_exact(&mut tag)?;
+        match tag[0] {
+            PAIR_TAG => {
+                let key = read_bytes(reader)?;
+                let value = read_bytes(reader)?;
+                let expiry = read_u64(reader)?;
+                f(key, value, Some(expiry).filter(|expiry| *expiry != 0))?;
+                count += 1;
+            }
+            END_TAG => break,
+            tag => {
+                return Err(DBError::DataError(format!(
+                    "invalid tag {} in export stream",
+                    tag
+                )))
+            }
+        }
+    }
+    let expected_count = read_u64(reader)?;
+    let checksum = reader.digest.clone().finalize();
+    let mut expected_checksum = [0_u8; 4];
+    reader.inner.read_exact(&mut expected_checksum)?;
+
+    Ok((
+        count,
+        checksum,
+        expected_count,
+        u32::from_be_bytes(expected_checksum),
+    ))
+}
+
+/// Same as [`import_binary`], for JSON Lines.
+fn import_json_lines<R: BufRead, F>(
+    reader: &mut ChecksumReader<R>,
+    f: &mut F,
+) -> Result<(u64, u32, u64, u32), DBError>
+where
+    F: FnMut(Key, Value, Option<u64>) -> Result<(), DBError>,
+{
+    let invalid = |line: &str| DBError::DataError(format!("invalid export line: {}", line));
+    let mut line = String::new();
+    let header = read_json_line(reader, &mut line)?;
+    if header["format"] != JSON_FORMAT {
+        return Err(invalid(&line));
+    }
+    check_version(header["version"].as_u64().ok_or_else(|| invalid(&line))?)?;
+
+    let mut count = 0;
+    loop {
+        let json = read_json_line(reader, &mut line)?;
+        if let Some(expected_checksum) = json.get("checksum") {
+            let expected_count = json["count"].as_u64().ok_or_else(|| invalid(&line))?;
+            let expected_checksum = expected_checksum
+                .as_u64()
+                .and_then(|checksum| u32::try_from(checksum).ok())
+                .ok_or_else(|| invalid(&line))?;
+            let checksum = reader.digest.clone().finalize();
+            return Ok((count, checksum, expected_count, expected_checksum));
+        }
+        let decode = |field: &str| {
+            json[field]
+                .as_str()
+                .and_then(|s| STANDARD.decode(s).ok())
+                .ok_or_else(|| invalid(&line))
+        };
+        let expiry = match json.get("expiry") {
+            Some(expiry) => Some(expiry.as_u64().ok_or_else(|| invalid(&line))?),
+            None => None,
+        };
+        f(decode("key")?, decode("value")?, expiry)?;
+        count += 1;
+    }
+}
+
+/// Reads the next line into `line` and parses it. The line is added to the
+/// checksum, unless it is the trailer, which is left for the caller.
+fn read_json_line<R: BufRead>(
+    reader: &mut ChecksumReader<R>,
+    line: &mut String,
+) -> Result<Json, DBError> {
+    line.clear();
+    if reader.inner.read_line(line)? == 0 {
+        return Err(io::Error::from(ErrorKind::UnexpectedEof).into());
+    }
+    let json: Json = serde_json::from_str(line)
+        .map_err(|_| DBError::DataError(format!("invalid export line: {}", line)))?;
+    if json.get("checksum").is_none() {
+        reader.digest.update(line.as_bytes());
+    }
+    Ok(json)
+}
+
+fn check_version(version: u64) -> Result<(), DBError> {
+    if version != VERSION as u64 {
+        return Err(DBError::VersionError(format!(
+            "export stream has format version {}, but only version {} is supported",
+            version, VERSION
+        )));
+    }
+    Ok(())
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> Result<u64, DBError> {
+    let mut bytes = [0_u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+/// Reads a length-prefixed byte string, without trusting the length to
+/// allocate up front.
+fn read_bytes<R: Read>(reader: &mut R) -> Result<Vec<u8>, DBError> {
+    let len = read_u64(reader)?;
+    let mut bytes = vec![];
+    reader.take(len).read_to_end(&mut bytes)?;
+    if (bytes.len() as u64) < len {
+        return Err(io::Error::from(ErrorKind::UnexpectedEof).into());
+    }
+    Ok(bytes)
+}
+
+/// Checksums everything written through it.
+struct ChecksumWriter<W: Write> {
+    inner: W,
+    digest: Digest<'static, u32>,
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.digest.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Checksums everything read through it. Reading from `inner` directly
+/// bypasses the checksum.
+struct ChecksumReader<R: BufRead> {
+    inner: R,
+    digest: Digest<'static, u32>,
+}
+
+impl<R: BufRead> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.digest.update(&buf[..read]);
+        Ok(read)
+    }
+}
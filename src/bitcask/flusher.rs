@@ -0,0 +1,94 @@
+//! Background thread behind [`SyncMode::Periodic`](super::opts::SyncMode).
+
+use std::{
+    io,
+    sync::{Arc, Condvar, Mutex, RwLock, Weak},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crate::error::DBError;
+
+use super::storage::Storage;
+
+#[derive(Default)]
+struct State {
+    /// Set to make the thread exit.
+    stop: bool,
+    /// Set to make the thread sync before its interval is over.
+    wake_up: bool,
+    /// The last failed sync, reported by the next call to [`Flusher::check`].
+    error: Option<io::Error>,
+}
+
+/// Syncs the active data file every `interval` and whenever
+/// [`Flusher::wake_up`] is called.
+pub(super) struct Flusher {
+    shared: Arc<(Mutex<State>, Condvar)>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Flusher {
+    pub(super) fn spawn(storage: Weak<RwLock<Storage>>, interval: Duration) -> Self {
+        let shared = Arc::new((Mutex::new(State::default()), Condvar::new()));
+        let thread_shared = shared.clone();
+        let handle = thread::spawn(move || {
+            let (state, condvar) = &*thread_shared;
+            loop {
+                let guard = state.lock().unwrap();
+                let (mut guard, _) = condvar
+                    .wait_timeout_while(guard, interval, |s| !s.stop && !s.wake_up)
+                    .unwrap();
+                if guard.stop {
+                    break;
+                }
+                guard.wake_up = false;
+                drop(guard);
+
+                let Some(storage) = storage.upgrade() else {
+                    break;
+                };
+                let res = storage.read().unwrap().sync();
+                if let Err(DBError::IOError(e)) = res {
+                    state.lock().unwrap().error = Some(e);
+                }
+            }
+        });
+
+        Self {
+            shared,
+            handle: Some(handle),
+        }
+    }
+
+    /// Asks for a sync right away.
+    pub(super) fn wake_up(&self) {
+        let (state, condvar) = &*self.shared;
+        state.lock().unwrap().wake_up = true;
+        condvar.notify_one();
+    }
+
+    /// Returns the error of a background sync that failed since the last call.
+    pub(super) fn check(&self) -> Result<(), DBError> {
+        match self.shared.0.lock().unwrap().error.take() {
+            Some(e) => Err(e.into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Stops the thread and waits for it to exit.
+    pub(super) fn stop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            let (state, condvar) = &*self.shared;
+            state.lock().unwrap().stop = true;
+            condvar.notify_one();
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for Flusher {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
@@ -0,0 +1,139 @@
+//! Ordered iteration over the keys of a database.
+
+use std::ops::Bound;
+
+use im::ordmap::ConsumingIter;
+
+use crate::error::DBError;
+
+use super::{keydir::KeyDirEntry, snapshot::Snapshot, Key, Value};
+
+/// Walks the keys of a snapshot in a range from both ends, skipping expired
+/// ones.
+struct Cursor {
+    snapshot: Snapshot,
+    /// The entries in the range that have not been visited yet.
+    entries: ConsumingIter<(Key, KeyDirEntry)>,
+}
+
+impl Cursor {
+    fn new(snapshot: Snapshot, lower: Bound<Key>, upper: Bound<Key>) -> Self {
+        let entries = snapshot.get_keydir().range(lower.as_ref(), upper.as_ref());
+        Self { snapshot, entries }
+    }
+
+    fn next(&mut self) -> Option<(Key, KeyDirEntry)> {
+        self.entries.find(|(_, entry)| !entry.is_expired())
+    }
+
+    fn next_back(&mut self) -> Option<(Key, KeyDirEntry)> {
+        self.entries.rfind(|(_, entry)| !entry.is_expired())
+    }
+
+    fn read(&self, (key, entry): (Key, KeyDirEntry)) -> Result<(Key, Value), DBError> {
+        let value = self.snapshot.read_value(&entry)?;
+        Ok((key, value))
+    }
+}
+
+/// Bounds matching exactly the keys starting with `prefix`.
+pub(super) fn prefix_bounds(prefix: &Key) -> (Bound<Key>, Bound<Key>) {
+    let mut upper = prefix.clone();
+    while let Some(last) = upper.pop() {
+        if last < u8::MAX {
+            upper.push(last + 1);
+            return (Bound::Included(prefix.clone()), Bound::Excluded(upper));
+        }
+    }
+    (Bound::Included(prefix.clone()), Bound::Unbounded)
+}
+
+/// Iterator over the key/value pairs with keys in a range, in key order.
+/// Returned by `iter`, `scan` and `scan_prefix` of
+/// [`BitCask`](super::BitCask) and [`Snapshot`]; use [`Iterator::rev`] to go
+/// from the largest key down.
+///
+/// It iterates over a snapshot, so writes made in the meantime are not seen.
+pub struct Iter {
+    cursor: Cursor,
+}
+
+impl Iter {
+    pub(super) fn new(snapshot: Snapshot, lower: Bound<Key>, upper: Bound<Key>) -> Self {
+        Self {
+            cursor: Cursor::new(snapshot, lower, upper),
+        }
+    }
+}
+
+impl Iterator for Iter {
+    type Item = Result<(Key, Value), DBError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let next = self.cursor.next()?;
+        Some(self.cursor.read(next))
+    }
+}
+
+impl DoubleEndedIterator for Iter {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let next = self.cursor.next_back()?;
+        Some(self.cursor.read(next))
+    }
+}
+
+/// Iterator over the keys, in order, returned by `keys` of
+/// [`BitCask`](super::BitCask) and [`Snapshot`]. Values are not read at all.
+pub struct Keys {
+    cursor: Cursor,
+}
+
+impl Keys {
+    pub(super) fn new(snapshot: Snapshot) -> Self {
+        Self {
+            cursor: Cursor::new(snapshot, Bound::Unbounded, Bound::Unbounded),
+        }
+    }
+}
+
+impl Iterator for Keys {
+    type Item = Key;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.cursor.next().map(|(key, _)| key)
+    }
+}
+
+impl DoubleEndedIterator for Keys {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.cursor.next_back().map(|(key, _)| key)
+    }
+}
+
+/// Iterator over the values in key order, returned by `values` of
+/// [`BitCask`](super::BitCask) and [`Snapshot`].
+pub struct Values {
+    iter: Iter,
+}
+
+impl Values {
+    pub(super) fn new(iter: Iter) -> Self {
+        Self { iter }
+    }
+}
+
+impl Iterator for Values {
+    type Item = Result<Value, DBError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next().map(|pair| pair.map(|(_, value)| value))
+    }
+}
+
+impl DoubleEndedIterator for Values {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.iter
+            .next_back()
+            .map(|pair| pair.map(|(_, value)| value))
+    }
+}
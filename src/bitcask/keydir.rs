@@ -1,32 +1,124 @@
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, mem, ops::Bound};
 
-use super::{FileId, Key, SizeType};
+use im::{ordmap::ConsumingIter, OrdMap, OrdSet};
 
+use super::{now_micros, stats::SizeHistogram, FileId, Key, SizeType};
+
+#[derive(Clone)]
 pub(super) struct KeyDirEntry {
     pub(super) file_id: FileId,
     pub(super) value_sz: SizeType,
     pub(super) value_pos: SizeType,
+    /// When the value was written, in microseconds since the Unix epoch.
+    pub(super) timestamp: u64,
+    /// When the value expires, in microseconds since the Unix epoch.
+    pub(super) expiry: Option<u64>,
 }
 
 impl KeyDirEntry {
-    pub(super) fn new(file_id: FileId, value_sz: SizeType, value_pos: SizeType) -> Self {
+    pub(super) fn new(
+        file_id: FileId,
+        value_sz: SizeType,
+        value_pos: SizeType,
+        timestamp: u64,
+        expiry: Option<u64>,
+    ) -> Self {
         Self {
             file_id,
             value_sz,
             value_pos,
+            timestamp,
+            expiry,
         }
     }
+
+    /// Whether the value has expired. An expired key is treated as deleted.
+    #[inline]
+    pub(super) fn is_expired(&self) -> bool {
+        self.expiry.is_some_and(|expiry| expiry <= now_micros())
+    }
 }
 
+/// Maps every live key to where its value is. The map is persistent: a
+/// clone shares all of it, and a change to either copies only the path to
+/// the changed key, which is how snapshots see the keydir as it was when
+/// they were taken without making writes copy it.
+#[derive(Clone, Default)]
 pub(super) struct KeyDir {
-    keydir: BTreeMap<Key, KeyDirEntry>,
+    keydir: OrdMap<Key, KeyDirEntry>,
+    /// The keys whose value has an expiry, by expiry.
+    expiries: OrdSet<(u64, Key)>,
+    /// When the keys deleted by [`KeyDir::delete_at`] that are not in the
+    /// map were deleted, so that a transaction notices a key it found absent
+    /// being written and deleted meanwhile.
+    deletions: OrdMap<Key, u64>,
+    /// Counts the times [`KeyDir::forget_deletions`] was called.
+    deletions_epoch: u64,
+    stats: KeyDirStats,
+}
+
+/// Counters over the entries of a keydir, kept up to date as it changes.
+#[derive(Clone, Default)]
+pub(super) struct KeyDirStats {
+    pub(super) keys: u64,
+    /// Total size of the keys.
+    pub(super) key_bytes: u64,
+    /// What the keydir points at in each data file.
+    pub(super) files: BTreeMap<FileId, LiveEntries>,
+    /// Number of keys by size, see [`SizeHistogram`].
+    pub(super) key_sizes: Vec<u64>,
+    /// Number of values by size, see [`SizeHistogram`].
+    pub(super) value_sizes: Vec<u64>,
+}
+
+/// The entries of a data file that hold the current value of a key.
+#[derive(Clone, Copy, Default)]
+pub(super) struct LiveEntries {
+    pub(super) entries: u64,
+    /// Total size of their keys and values.
+    pub(super) bytes: u64,
+    /// Number of them with an expiry.
+    pub(super) expiring: u64,
+}
+
+impl KeyDirStats {
+    fn add(&mut self, key: &Key, entry: &KeyDirEntry) {
+        self.keys += 1;
+        self.key_bytes += key.len() as u64;
+        let live = self.files.entry(entry.file_id).or_default();
+        live.entries += 1;
+        live.bytes += key.len() as u64 + entry.value_sz;
+        live.expiring += entry.expiry.is_some() as u64;
+        Self::count(&mut self.key_sizes, key.len() as u64, 1);
+        Self::count(&mut self.value_sizes, entry.value_sz, 1);
+    }
+
+    fn remove(&mut self, key: &Key, entry: &KeyDirEntry) {
+        self.keys -= 1;
+        self.key_bytes -= key.len() as u64;
+        let live = self.files.get_mut(&entry.file_id).unwrap();
+        live.entries -= 1;
+        live.bytes -= key.len() as u64 + entry.value_sz;
+        live.expiring -= entry.expiry.is_some() as u64;
+        if live.entries == 0 {
+            self.files.remove(&entry.file_id);
+        }
+        Self::count(&mut self.key_sizes, key.len() as u64, -1);
+        Self::count(&mut self.value_sizes, entry.value_sz, -1);
+    }
+
+    fn count(histogram: &mut Vec<u64>, size: u64, delta: i64) {
+        let bucket = SizeHistogram::bucket(size);
+        if histogram.len() <= bucket {
+            histogram.resize(bucket + 1, 0);
+        }
+        histogram[bucket] = histogram[bucket].wrapping_add_signed(delta);
+    }
 }
 
 impl KeyDir {
     pub(super) fn new() -> Self {
-        Self {
-            keydir: BTreeMap::new(),
-        }
+        Self::default()
     }
 
     pub(super) fn get(&self, key: &Key) -> Option<&KeyDirEntry> {
@@ -34,14 +126,124 @@ impl KeyDir {
     }
 
     pub(super) fn put(&mut self, key: Key, entry: KeyDirEntry) -> Option<KeyDirEntry> {
-        self.keydir.insert(key, entry)
+        self.deletions.remove(&key);
+        let old_entry = self.keydir.insert(key.clone(), entry.clone());
+        if let Some(old_entry) = &old_entry {
+            self.forget(&key, old_entry);
+        }
+        self.stats.add(&key, &entry);
+        if let Some(expiry) = entry.expiry {
+            self.expiries.insert((expiry, key));
+        }
+        old_entry
     }
 
     pub(super) fn delete(&mut self, key: &Key) -> Option<KeyDirEntry> {
-        self.keydir.remove(key)
+        let old_entry = self.keydir.remove(key);
+        if let Some(old_entry) = &old_entry {
+            self.forget(key, old_entry);
+        }
+        old_entry
     }
 
-    pub(super) fn list_keys(&self) -> Vec<Key> {
-        self.keydir.keys().cloned().collect()
+    /// Deletes `key` and records that it was deleted at `timestamp`.
+    pub(super) fn delete_at(&mut self, key: &Key, timestamp: u64) {
+        self.delete(key);
+        self.deletions.insert(key.clone(), timestamp);
+    }
+
+    /// Returns the timestamp of the latest write to `key`, be it a value or a
+    /// deletion recorded by [`KeyDir::delete_at`], if there is one.
+    pub(super) fn last_write(&self, key: &Key) -> Option<u64> {
+        match self.keydir.get(key) {
+            Some(entry) => Some(entry.timestamp),
+            None => self.deletions.get(key).copied(),
+        }
+    }
+
+    /// Lets go of the recorded deletions, which move on to a new epoch.
+    pub(super) fn forget_deletions(&mut self) {
+        self.deletions = OrdMap::new();
+        self.deletions_epoch += 1;
+    }
+
+    #[inline]
+    pub(super) fn get_deletions_epoch(&self) -> u64 {
+        self.deletions_epoch
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.keydir.len()
+    }
+
+    /// Returns the counters over the entries that have not expired. An
+    /// expired value is dead even though it is still in the map, which
+    /// only drops it once the key is written again or a restart replays it.
+    pub(super) fn get_stats(&self) -> KeyDirStats {
+        let mut stats = self.stats.clone();
+        let now = (now_micros() + 1, Key::new());
+        for (_, key) in self.expiries.range(..now) {
+            stats.remove(key, &self.keydir[key]);
+        }
+        stats
+    }
+
+    /// Estimates the memory used by the map, the expiries and the
+    /// deletions: the live keys, and every slot with about half as much
+    /// again for the room left in, and the pointers between, the nodes of
+    /// the trees. The deleted keys themselves are not counted.
+    pub(super) fn estimate_memory(&self) -> u64 {
+        let slot_sz = (mem::size_of::<Key>() + mem::size_of::<KeyDirEntry>()) as u64;
+        let expiry_slot_sz = (mem::size_of::<u64>() + mem::size_of::<Key>()) as u64;
+        let slots_sz = self.len() as u64 * slot_sz
+            + (self.expiries.len() + self.deletions.len()) as u64 * expiry_slot_sz;
+        slots_sz * 3 / 2 + self.stats.key_bytes
+    }
+
+    /// Returns the entries with keys between `lower` and `upper`, in key
+    /// order from either end. The iterator shares the part of the map it has
+    /// yet to visit instead of borrowing the map, so it can be kept along
+    /// with it.
+    pub(super) fn range(
+        &self,
+        lower: Bound<&Key>,
+        upper: Bound<&Key>,
+    ) -> ConsumingIter<(Key, KeyDirEntry)> {
+        let map = match lower {
+            Bound::Included(key) => {
+                let (_, entry, mut map) = self.keydir.split_lookup(key);
+                if let Some(entry) = entry {
+                    map.insert(key.clone(), entry);
+                }
+                map
+            }
+            Bound::Excluded(key) => self.keydir.split(key).1,
+            Bound::Unbounded => self.keydir.clone(),
+        };
+        let map = match upper {
+            Bound::Included(key) => {
+                let (mut map, entry, _) = map.split_lookup(key);
+                if let Some(entry) = entry {
+                    map.insert(key.clone(), entry);
+                }
+                map
+            }
+            Bound::Excluded(key) => map.split(key).0,
+            Bound::Unbounded => map,
+        };
+        map.into_iter()
+    }
+
+    pub(super) fn iter(&self) -> impl Iterator<Item = (&Key, &KeyDirEntry)> {
+        self.keydir.iter()
+    }
+
+    /// Takes the entry `key` no longer has out of the counters and the
+    /// expiries.
+    fn forget(&mut self, key: &Key, old_entry: &KeyDirEntry) {
+        self.stats.remove(key, old_entry);
+        if let Some(expiry) = old_entry.expiry {
+            self.expiries.remove(&(expiry, key.clone()));
+        }
     }
 }
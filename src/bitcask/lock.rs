@@ -0,0 +1,81 @@
+//! Advisory locking of the data directory, so that a writer never shares it.
+
+use std::{
+    fs::{File, OpenOptions, TryLockError},
+    io::ErrorKind,
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::error::DBError;
+
+/// A `flock` on the `LOCK` file of a data directory: exclusive for a writer
+/// and shared for readers. It is released when dropped.
+pub(super) struct DirLock {
+    _file: File,
+}
+
+impl DirLock {
+    const FILE_NAME: &'static str = "LOCK";
+    const RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+    /// Takes the lock, waiting up to `timeout` for whoever holds it, or not
+    /// at all without a timeout. Readers never create the lock file, so they
+    /// go without a lock if there is none, since no writer has ever opened
+    /// the directory, or if the filesystem is read-only, where no writer can.
+    /// A lock file they may not open is an error, as a writer may hold it.
+    pub(super) fn acquire(
+        data_dir: &Path,
+        exclusive: bool,
+        timeout: Option<Duration>,
+    ) -> Result<Option<Self>, DBError> {
+        let path = data_dir.join(Self::FILE_NAME);
+        let file = if exclusive {
+            OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(path)?
+        } else {
+            match File::open(path) {
+                Ok(file) => file,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        ErrorKind::NotFound | ErrorKind::ReadOnlyFilesystem
+                    ) =>
+                {
+                    return Ok(None)
+                }
+                Err(e) => return Err(e.into()),
+            }
+        };
+        let deadline = Instant::now() + timeout.unwrap_or_default();
+        loop {
+            let res = if exclusive {
+                file.try_lock()
+            } else {
+                file.try_lock_shared()
+            };
+            match res {
+                Ok(()) => return Ok(Some(Self { _file: file })),
+                Err(TryLockError::WouldBlock) if Instant::now() < deadline => {
+                    thread::sleep(Self::RETRY_INTERVAL)
+                }
+                Err(TryLockError::WouldBlock) => {
+                    return Err(DBError::LockError(format!(
+                        "{} is in use by {}",
+                        data_dir.display(),
+                        if exclusive {
+                            "another instance"
+                        } else {
+                            "a writer"
+                        }
+                    )))
+                }
+                Err(TryLockError::Error(e)) => return Err(e.into()),
+            }
+        }
+    }
+}
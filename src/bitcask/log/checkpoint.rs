@@ -0,0 +1,143 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, ErrorKind, Write},
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    bitcask::{FileId, SizeType},
+    error::DBError,
+};
+
+use super::{
+    file_set::{self, CHUNK_SIZE},
+    hint_file::HintFile,
+    sync_dir, FileSet,
+};
+
+/// The data files of the log at one point in time, written out by
+/// [`Checkpoint::write`]. They are held open, so a merge that replaces them
+/// in the meantime does not get in the way.
+pub(crate) struct Checkpoint {
+    file_set: FileSet,
+    files: BTreeMap<FileId, CheckpointFile>,
+}
+
+pub(super) struct CheckpointFile {
+    path: PathBuf,
+    /// Number of valid bytes if the file may still grow. Only those are
+    /// copied, while immutable files are linked.
+    valid_sz: Option<SizeType>,
+}
+
+impl CheckpointFile {
+    pub(super) fn new(path: PathBuf, valid_sz: Option<SizeType>) -> Self {
+        Self { path, valid_sz }
+    }
+}
+
+impl Checkpoint {
+    pub(super) fn new(file_set: FileSet, files: BTreeMap<FileId, CheckpointFile>) -> Self {
+        Self { file_set, files }
+    }
+
+    /// Writes the files into `dest_dir`, which must be empty or not exist.
+    pub(crate) fn write(&self, dest_dir: &Path) -> Result<(), DBError> {
+        fs::create_dir_all(dest_dir)?;
+        if fs::read_dir(dest_dir)?.next().is_some() {
+            return Err(io::Error::new(
+                ErrorKind::AlreadyExists,
+                format!("{} is not empty", dest_dir.display()),
+            )
+            .into());
+        }
+        for (file_id, checkpoint_file) in &self.files {
+            let dest_path = dest_dir.join(checkpoint_file.path.file_name().unwrap());
+            let dest_hint_path = dest_path.with_extension(HintFile::EXTENSION);
+            self.write_file(*file_id, &dest_path, &dest_hint_path, true)?;
+        }
+        sync_dir(dest_dir)
+    }
+
+    /// Returns the ids of the files along with the number of bytes each one
+    /// is written with.
+    pub(crate) fn file_sizes(&self) -> Result<Vec<(FileId, SizeType)>, DBError> {
+        self.files
+            .iter()
+            .map(|(file_id, checkpoint_file)| {
+                let file_sz = match checkpoint_file.valid_sz {
+                    Some(valid_sz) => valid_sz,
+                    None => self.file_set.get_file(*file_id).metadata()?.len(),
+                };
+                Ok((*file_id, file_sz))
+            })
+            .collect()
+    }
+
+    /// Returns the CRC-32 of the bytes of file `file_id` that are written,
+    /// of which there are `file_sz`.
+    pub(crate) fn checksum(&self, file_id: FileId, file_sz: SizeType) -> Result<u32, DBError> {
+        file_set::checksum(self.file_set.get_file(file_id), file_sz)
+    }
+
+    /// Returns the CRC-32 of the immutable file `file_id` recorded in its
+    /// hint file when it was sealed, if it has a valid one.
+    pub(crate) fn sealed_checksum(&self, file_id: FileId) -> Option<u32> {
+        let checkpoint_file = &self.files[&file_id];
+        if checkpoint_file.valid_sz.is_some() {
+            return None;
+        }
+        HintFile::load_checksum(&checkpoint_file.path.with_extension(HintFile::EXTENSION))
+    }
+
+    /// Writes one file to `dest_path`, and its hint, if it has one, to
+    /// `dest_hint_path`. Returns whether there was a hint. Immutable files
+    /// and their hints are hard-linked if `link` is set, and copied if not or
+    /// if that is not possible, e.g. across filesystems or because a merge
+    /// deleted them.
+    pub(crate) fn write_file(
+        &self,
+        file_id: FileId,
+        dest_path: &Path,
+        dest_hint_path: &Path,
+        link: bool,
+    ) -> Result<bool, DBError> {
+        let checkpoint_file = &self.files[&file_id];
+        let file = self.file_set.get_file(file_id);
+        if let Some(valid_sz) = checkpoint_file.valid_sz {
+            copy_prefix(file, dest_path, valid_sz)?;
+            return Ok(false);
+        }
+        if !link || fs::hard_link(&checkpoint_file.path, dest_path).is_err() {
+            copy_prefix(file, dest_path, file.metadata()?.len())?;
+        }
+        file.sync_all()?;
+        // Without its hint the data file is scanned when opened.
+        let hint_path = checkpoint_file.path.with_extension(HintFile::EXTENSION);
+        if !link || fs::hard_link(&hint_path, dest_hint_path).is_err() {
+            match fs::copy(&hint_path, dest_hint_path) {
+                Ok(_) => File::open(dest_hint_path)?.sync_all()?,
+                Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Copies the first `len` bytes of `file` into a new file at `dest_path`.
+fn copy_prefix(file: &File, dest_path: &Path, len: SizeType) -> Result<(), DBError> {
+    let mut dest_file = File::create(dest_path)?;
+    let mut buf = vec![];
+    let mut offset = 0;
+    while offset < len {
+        buf.resize(CHUNK_SIZE.min(len - offset) as usize, 0);
+        file.read_exact_at(&mut buf, offset)?;
+        dest_file.write_all(&buf)?;
+        offset += buf.len() as SizeType;
+    }
+    dest_file.sync_all()?;
+    Ok(())
+}
@@ -0,0 +1,129 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, ErrorKind, Read},
+    os::unix::fs::FileExt,
+    path::PathBuf,
+    sync::{Arc, Mutex, Weak},
+};
+
+use crc::{Crc, CRC_32_CKSUM};
+
+use crate::{
+    bitcask::{keydir::KeyDirEntry, FileId, SizeType, Value},
+    error::DBError,
+};
+
+/// How much of a file is read at once.
+pub(super) const CHUNK_SIZE: SizeType = 1 << 20;
+static CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_CKSUM);
+
+/// Read handles on the data files of the log at one point in time. Clones
+/// share the handles, which keep the files from being deleted by a merge.
+#[derive(Clone)]
+pub(crate) struct FileSet {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    files: BTreeMap<FileId, Arc<File>>,
+    retired: Arc<Retired>,
+}
+
+impl FileSet {
+    pub(super) fn new(files: BTreeMap<FileId, Arc<File>>, retired: Arc<Retired>) -> Self {
+        Self {
+            inner: Arc::new(Inner { files, retired }),
+        }
+    }
+
+    pub(crate) fn read_value(&self, keydir_entry: &KeyDirEntry) -> Result<Value, DBError> {
+        read_value(self.get_file(keydir_entry.file_id), keydir_entry)
+    }
+
+    #[inline]
+    pub(super) fn get_file(&self, file_id: FileId) -> &File {
+        &self.inner.files[&file_id]
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        self.files.clear();
+        self.retired.release();
+    }
+}
+
+/// Data files replaced by a merge while a [`FileSet`] still had them open.
+/// They are no longer part of the database and are deleted once the last
+/// file set using them is gone.
+#[derive(Default)]
+pub(super) struct Retired {
+    files: Mutex<Vec<(PathBuf, Weak<File>)>>,
+}
+
+impl Retired {
+    pub(super) fn add(&self, path: PathBuf, file: Weak<File>) {
+        self.files.lock().unwrap().push((path, file));
+    }
+
+    /// Deletes the files nothing reads from anymore. A file that cannot be
+    /// deleted is tried again next time, and at the latest when the database
+    /// is opened again.
+    pub(super) fn release(&self) {
+        self.files.lock().unwrap().retain(|(path, file)| {
+            file.strong_count() > 0
+                || matches!(fs::remove_file(path), Err(e) if e.kind() != ErrorKind::NotFound)
+        });
+    }
+}
+
+/// Reads the value `keydir_entry` points to in `file`.
+pub(super) fn read_value(file: &File, keydir_entry: &KeyDirEntry) -> Result<Value, DBError> {
+    let mut buf = vec![0; keydir_entry.value_sz as usize];
+    file.read_exact_at(&mut buf, keydir_entry.value_pos)?;
+    Ok(buf)
+}
+
+/// Returns the CRC-32 of the first `len` bytes of `file`.
+pub(super) fn checksum(file: &File, len: SizeType) -> Result<u32, DBError> {
+    let mut digest = CRC32.digest();
+    let mut buf = vec![];
+    let mut offset = 0;
+    while offset < len {
+        buf.resize(CHUNK_SIZE.min(len - offset) as usize, 0);
+        file.read_exact_at(&mut buf, offset)?;
+        digest.update(&buf);
+        offset += buf.len() as SizeType;
+    }
+    Ok(digest.finalize())
+}
+
+/// Returns the CRC-32 of some bytes followed by `bytes`, given the CRC-32
+/// `checksum` of the former.
+pub(super) fn extend_checksum(checksum: u32, bytes: &[u8]) -> u32 {
+    let mut digest = CRC32.digest_with_initial(checksum ^ CRC_32_CKSUM.xorout);
+    digest.update(bytes);
+    digest.finalize()
+}
+
+/// Reads a file from `pos` on without moving its cursor, which the writer
+/// shares.
+pub(super) struct PositionedReader<'a> {
+    file: &'a File,
+    pos: SizeType,
+}
+
+impl<'a> PositionedReader<'a> {
+    pub(super) fn new(file: &'a File, pos: SizeType) -> Self {
+        Self { file, pos }
+    }
+}
+
+impl Read for PositionedReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.file.read_at(buf, self.pos)?;
+        self.pos += read as SizeType;
+        Ok(read)
+    }
+}
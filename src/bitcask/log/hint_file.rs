@@ -0,0 +1,281 @@
+use std::{
+    fs,
+    io::{Read, Write},
+    path::Path,
+};
+
+use crc::{Crc, CRC_32_CKSUM};
+
+use crate::{
+    bitcask::{
+        keydir::{KeyDir, KeyDirEntry},
+        now_micros, FileId, Key, SizeType,
+    },
+    error::DBError,
+};
+
+use super::log_entry::{Deserialize, LogEntry, Serialize};
+
+/// One record of a hint file. It carries everything needed to rebuild a
+/// [`KeyDirEntry`] without reading the value from the data file.
+#[derive(Debug)]
+pub(super) struct HintEntry {
+    key: Key,
+    file_id: FileId,
+    value_sz: SizeType,
+    value_pos: SizeType,
+    timestamp: u64,
+    /// The flags of the entry, see [`LogEntry::get_flags`].
+    flags: u8,
+    expiry: Option<u64>,
+}
+
+impl HintEntry {
+    pub(super) fn new(
+        key: Key,
+        file_id: FileId,
+        value_sz: SizeType,
+        value_pos: SizeType,
+        timestamp: u64,
+        flags: u8,
+        expiry: Option<u64>,
+    ) -> Self {
+        Self {
+            key,
+            file_id,
+            value_sz,
+            value_pos,
+            timestamp,
+            flags,
+            expiry,
+        }
+    }
+
+    #[inline]
+    pub(super) fn get_key(self) -> Key {
+        self.key
+    }
+
+    #[inline]
+    pub(super) fn set_file_id(&mut self, file_id: FileId) {
+        self.file_id = file_id;
+    }
+
+    #[inline]
+    pub(super) fn get_timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    #[inline]
+    pub(super) fn is_tombstone(&self) -> bool {
+        self.flags & LogEntry::TOMBSTONE_FLAG != 0
+    }
+
+    /// Whether this record deletes its key, being a tombstone or a value that
+    /// has expired.
+    #[inline]
+    pub(super) fn is_deletion(&self) -> bool {
+        self.is_tombstone() || self.expiry.is_some_and(|expiry| expiry <= now_micros())
+    }
+
+    /// Replays this record on top of `keydir`. An expired value still
+    /// shadows older ones, so it deletes the key.
+    fn apply(self, keydir: &mut KeyDir) {
+        if self.is_deletion() {
+            keydir.delete(&self.key);
+        } else {
+            let keydir_entry = KeyDirEntry::new(
+                self.file_id,
+                self.value_sz,
+                self.value_pos,
+                self.timestamp,
+                self.expiry,
+            );
+            keydir.put(self.key, keydir_entry);
+        }
+    }
+}
+
+/// Replays [`HintEntry`]s into a keydir in log order. The entries of a batch
+/// are held back until its last entry shows up, so that a batch cut short by
+/// a crash leaves no trace, even if it spans several data files.
+pub(super) struct Replayer<'a> {
+    keydir: &'a mut KeyDir,
+    /// The entries of the batch being replayed, if any.
+    batch: Option<Vec<HintEntry>>,
+}
+
+impl<'a> Replayer<'a> {
+    pub(super) fn new(keydir: &'a mut KeyDir) -> Self {
+        Self::resume(keydir, None)
+    }
+
+    /// Carries on where a replayer that ended with `batch` pending left off,
+    /// for when the rest of the batch has not been written at that time.
+    pub(super) fn resume(keydir: &'a mut KeyDir, batch: Option<Vec<HintEntry>>) -> Self {
+        Self { keydir, batch }
+    }
+
+    /// Returns the entries of the batch that has not finished yet, if any.
+    pub(super) fn into_batch(self) -> Option<Vec<HintEntry>> {
+        self.batch
+    }
+
+    pub(super) fn replay(&mut self, hint: HintEntry) {
+        let flags = hint.flags;
+        if flags & LogEntry::BATCH_FLAG == 0 {
+            // Nothing is written between the entries of a batch, so the
+            // batch being replayed never finished.
+            self.batch = None;
+            hint.apply(self.keydir);
+            return;
+        }
+        if flags & LogEntry::BATCH_START_FLAG != 0 {
+            self.batch = Some(vec![]);
+        }
+        if let Some(batch) = &mut self.batch {
+            batch.push(hint);
+            if flags & LogEntry::BATCH_END_FLAG != 0 {
+                for hint in self.batch.take().unwrap() {
+                    hint.apply(self.keydir);
+                }
+            }
+        }
+    }
+}
+
+impl Serialize for HintEntry {
+    fn serialize<T: Write>(&self, buf: &mut T) -> Result<(), DBError> {
+        buf.write_all(&[self.flags])?;
+        buf.write_all(&(self.file_id as SizeType).to_be_bytes())?;
+        buf.write_all(&self.value_sz.to_be_bytes())?;
+        buf.write_all(&self.value_pos.to_be_bytes())?;
+        buf.write_all(&self.timestamp.to_be_bytes())?;
+        buf.write_all(&self.expiry.unwrap_or(0).to_be_bytes())?;
+        buf.write_all(&(self.key.len() as SizeType).to_be_bytes())?;
+        buf.write_all(&self.key)?;
+
+        Ok(())
+    }
+}
+
+impl Deserialize for HintEntry {
+    fn deserialize<T: Read>(buf: &mut T) -> Result<Self, DBError>
+    where
+        Self: Sized,
+    {
+        let mut flags_buf = [0_u8; 1];
+        buf.read_exact(&mut flags_buf)?;
+        let mut size_buf = [0_u8; HintFile::SIZE_SIZE];
+        buf.read_exact(&mut size_buf)?;
+        let file_id = SizeType::from_be_bytes(size_buf) as FileId;
+        buf.read_exact(&mut size_buf)?;
+        let value_sz = SizeType::from_be_bytes(size_buf);
+        buf.read_exact(&mut size_buf)?;
+        let value_pos = SizeType::from_be_bytes(size_buf);
+        buf.read_exact(&mut size_buf)?;
+        let timestamp = u64::from_be_bytes(size_buf);
+        buf.read_exact(&mut size_buf)?;
+        let expiry = Some(u64::from_be_bytes(size_buf)).filter(|expiry| *expiry != 0);
+        buf.read_exact(&mut size_buf)?;
+        let key_size = SizeType::from_be_bytes(size_buf);
+        let mut key = vec![0_u8; key_size as usize];
+        buf.read_exact(&mut key)?;
+
+        Ok(Self {
+            key,
+            file_id,
+            value_sz,
+            value_pos,
+            timestamp,
+            flags: flags_buf[0],
+            expiry,
+        })
+    }
+}
+
+/// A hint file starts with [`HintFile::MAGIC`], its big-endian format version
+/// and the CRC32 of its data file, lists the [`HintEntry`]s of that sealed
+/// data file, and ends with a CRC32 of everything before it.
+pub(super) struct HintFile;
+
+impl HintFile {
+    pub(super) const EXTENSION: &'static str = "hint";
+    const MAGIC: [u8; 4] = *b"TDBH";
+    /// Hints in any other version are ignored and rebuilt from the data file.
+    const VERSION: u32 = 1;
+    const CHECKSUM_SIZE: usize = 4;
+    const SIZE_SIZE: usize = SizeType::BITS as usize / 8;
+    const CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_CKSUM);
+
+    /// Writes the hint file at `path` for a data file whose contents have
+    /// the CRC32 `data_checksum`.
+    pub(super) fn write(
+        path: &Path,
+        data_checksum: u32,
+        entries: &[HintEntry],
+    ) -> Result<(), DBError> {
+        let mut buf = vec![];
+        buf.write_all(&Self::MAGIC)?;
+        buf.write_all(&Self::VERSION.to_be_bytes())?;
+        buf.write_all(&data_checksum.to_be_bytes())?;
+        for entry in entries {
+            entry.serialize(&mut buf)?;
+        }
+        let checksum = Self::CRC32.checksum(&buf);
+        buf.write_all(&checksum.to_be_bytes())?;
+        fs::write(path, buf)?;
+
+        Ok(())
+    }
+
+    /// Loads the hint file at `path`. Returns `None` if it is missing,
+    /// unreadable or fails its checksum, in which case the caller should fall
+    /// back to scanning the data file.
+    pub(super) fn load(path: &Path) -> Option<Vec<HintEntry>> {
+        let buf = Self::read(path)?;
+        let (_, mut reader) = Self::split_header(&buf)?;
+        let mut entries = vec![];
+        while !reader.is_empty() {
+            entries.push(HintEntry::deserialize(&mut reader).ok()?);
+        }
+        Some(entries)
+    }
+
+    /// Returns the CRC32 of the data file recorded in the hint file at
+    /// `path`, or `None` if [`HintFile::load`] would not load it.
+    pub(super) fn load_checksum(path: &Path) -> Option<u32> {
+        let buf = Self::read(path)?;
+        Self::split_header(&buf).map(|(data_checksum, _)| data_checksum)
+    }
+
+    /// Reads the hint file at `path` and returns it without its checksum if
+    /// that matches.
+    fn read(path: &Path) -> Option<Vec<u8>> {
+        let mut buf = fs::read(path).ok()?;
+        if buf.len() < Self::CHECKSUM_SIZE {
+            return None;
+        }
+        let (body, checksum) = buf.split_at(buf.len() - Self::CHECKSUM_SIZE);
+        if Self::CRC32.checksum(body).to_be_bytes() != checksum {
+            return None;
+        }
+        buf.truncate(buf.len() - Self::CHECKSUM_SIZE);
+        Some(buf)
+    }
+
+    /// Splits the header, which gives the checksum of the data file, from
+    /// the entries that follow it.
+    fn split_header(body: &[u8]) -> Option<(u32, &[u8])> {
+        let (header, entries) = body.split_at_checked(Self::MAGIC.len() + 8)?;
+        let (magic, header) = header.split_at(Self::MAGIC.len());
+        let (version, data_checksum) = header.split_at(4);
+        if magic != Self::MAGIC || version != Self::VERSION.to_be_bytes() {
+            return None;
+        }
+        Some((
+            u32::from_be_bytes(data_checksum.try_into().unwrap()),
+            entries,
+        ))
+    }
+}
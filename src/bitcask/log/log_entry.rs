@@ -19,21 +19,56 @@ pub(super) trait Deserialize {
         Self: Sized;
 }
 
+/// An entry of a data file. The layout of the current format version is
+///
+/// | checksum | timestamp | flags | expiry | key size | value size | header checksum | key | value |
+///
+/// where the expiry is only there if [`LogEntry::EXPIRY_FLAG`] is set. The
+/// header checksum covers the fields before it but the first, so that a
+/// damaged header is told apart without reading the key and value. Files
+/// written before format versions were introduced have no timestamp, flags
+/// or expiry, and mark tombstones with an empty value.
 #[derive(Clone)]
 pub(super) struct LogEntry {
+    version: u32,
     checksum: u32,
+    timestamp: u64,
+    flags: u8,
+    /// When the entry expires, in microseconds since the Unix epoch.
+    expiry: Option<u64>,
     key: Key,
     value: Option<Value>,
 }
 
 impl LogEntry {
+    /// Format of the headerless files written before versioning.
+    pub(super) const LEGACY_VERSION: u32 = 0;
+    /// Format written by this version of tdb.
+    pub(super) const VERSION: u32 = 1;
+    pub(super) const TOMBSTONE_FLAG: u8 = 0b1;
+    /// Set on every entry written by a [`WriteBatch`](crate::bitcask::batch::WriteBatch).
+    pub(super) const BATCH_FLAG: u8 = 0b10;
+    /// Set on the first entry of a batch.
+    pub(super) const BATCH_START_FLAG: u8 = 0b100;
+    /// Set on the last entry of a batch. The batch takes effect only once
+    /// this entry is on disk.
+    pub(super) const BATCH_END_FLAG: u8 = 0b1000;
+    /// Set on entries written with a TTL.
+    const EXPIRY_FLAG: u8 = 0b10000;
     const CHECKSUM_SIZE: SizeType = 4;
+    const TIMESTAMP_SIZE: SizeType = 8;
+    const EXPIRY_SIZE: SizeType = 8;
+    const FLAGS_SIZE: SizeType = 1;
     const SIZE_SIZE: SizeType = SizeType::BITS as SizeType / 8;
     const CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_CKSUM);
 
-    pub(super) fn new_live_entry(key: Key, value: Value) -> Self {
+    pub(super) fn new_live_entry(key: Key, value: Value, timestamp: u64) -> Self {
         let mut entry = LogEntry {
+            version: Self::VERSION,
             checksum: 0,
+            timestamp,
+            flags: 0,
+            expiry: None,
             key,
             value: Some(value),
         };
@@ -42,9 +77,13 @@ impl LogEntry {
         entry
     }
 
-    pub(super) fn new_tombstone_entry(key: Key) -> LogEntry {
+    pub(super) fn new_tombstone_entry(key: Key, timestamp: u64) -> LogEntry {
         let mut entry = LogEntry {
+            version: Self::VERSION,
             checksum: 0,
+            timestamp,
+            flags: Self::TOMBSTONE_FLAG,
+            expiry: None,
             key,
             value: None,
         };
@@ -53,6 +92,115 @@ impl LogEntry {
         entry
     }
 
+    /// Marks this entry as part of a batch. `first` and `last` tell where in
+    /// the batch it is.
+    pub(super) fn in_batch(mut self, first: bool, last: bool) -> Self {
+        self.flags |= Self::BATCH_FLAG;
+        if first {
+            self.flags |= Self::BATCH_START_FLAG;
+        }
+        if last {
+            self.flags |= Self::BATCH_END_FLAG;
+        }
+        self.checksum = self.calculate_checksum();
+
+        self
+    }
+
+    /// Makes this entry expire at `expiry`, in microseconds since the Unix
+    /// epoch.
+    pub(super) fn with_expiry(mut self, expiry: u64) -> Self {
+        self.flags |= Self::EXPIRY_FLAG;
+        self.expiry = Some(expiry);
+        self.checksum = self.calculate_checksum();
+
+        self
+    }
+
+    /// Reads an entry written in format `version`. An entry whose header
+    /// fails its checksum, or claiming to be longer than `max_size` bytes, is
+    /// reported as corrupt before its key and value are read.
+    pub(super) fn deserialize_version<T: Read>(
+        buf: &mut T,
+        version: u32,
+        max_size: SizeType,
+    ) -> Result<Self, DBError> {
+        let entry = Self::deserialize_unchecked(buf, version, max_size)?;
+        if entry.is_valid() {
+            Ok(entry)
+        } else {
+            Err(DBError::DataError("invalid checksum".to_string()))
+        }
+    }
+
+    /// Same as [`LogEntry::deserialize_version`], but also returns an entry
+    /// that fails its checksum. Check it with [`LogEntry::is_valid`].
+    pub(super) fn deserialize_unchecked<T: Read>(
+        buf: &mut T,
+        version: u32,
+        max_size: SizeType,
+    ) -> Result<Self, DBError> {
+        let mut checksum_buf = [0_u8; Self::CHECKSUM_SIZE as usize];
+        buf.read_exact(&mut checksum_buf)?;
+        let checksum = u32::from_be_bytes(checksum_buf);
+        let mut size_buf = [0_u8; Self::SIZE_SIZE as usize];
+        let legacy = version == Self::LEGACY_VERSION;
+        let (timestamp, flags) = if legacy {
+            (0, 0)
+        } else {
+            buf.read_exact(&mut size_buf)?;
+            let mut flags_buf = [0_u8; Self::FLAGS_SIZE as usize];
+            buf.read_exact(&mut flags_buf)?;
+            (u64::from_be_bytes(size_buf), flags_buf[0])
+        };
+        let expiry = if flags & Self::EXPIRY_FLAG != 0 {
+            buf.read_exact(&mut size_buf)?;
+            Some(u64::from_be_bytes(size_buf))
+        } else {
+            None
+        };
+        buf.read_exact(&mut size_buf)?;
+        let key_size = SizeType::from_be_bytes(size_buf);
+        buf.read_exact(&mut size_buf)?;
+        let value_size = SizeType::from_be_bytes(size_buf);
+        if !legacy {
+            let mut checksum_buf = [0_u8; Self::CHECKSUM_SIZE as usize];
+            buf.read_exact(&mut checksum_buf)?;
+            let header_checksum =
+                Self::header_checksum(timestamp, flags, expiry, key_size, value_size);
+            if u32::from_be_bytes(checksum_buf) != header_checksum {
+                return Err(DBError::DataError("invalid header checksum".to_string()));
+            }
+        }
+        if key_size.saturating_add(value_size) > max_size {
+            return Err(DBError::DataError("invalid entry size".to_string()));
+        }
+        let mut key_buf = vec![0_u8; key_size as usize];
+        buf.read_exact(&mut key_buf)?;
+        let mut value_buf = vec![0_u8; value_size as usize];
+        buf.read_exact(&mut value_buf)?;
+        let flags = if legacy && value_size == 0 {
+            Self::TOMBSTONE_FLAG
+        } else {
+            flags
+        };
+        let value = if flags & Self::TOMBSTONE_FLAG != 0 {
+            None
+        } else {
+            Some(value_buf)
+        };
+
+        Ok(Self {
+            version,
+            checksum,
+            timestamp,
+            flags,
+            expiry,
+            key: key_buf,
+            value,
+        })
+    }
+
     #[inline]
     fn key_size(&self) -> SizeType {
         self.key.len() as u64
@@ -65,12 +213,12 @@ impl LogEntry {
 
     #[inline]
     pub(super) fn total_size(&self) -> SizeType {
-        Self::CHECKSUM_SIZE + Self::SIZE_SIZE * 2 + self.key_size() + self.value_size()
+        self.header_size() + self.key_size() + self.value_size()
     }
 
     #[inline]
-    pub(super) fn is_tombstone(&self) -> bool {
-        self.value.is_none()
+    pub(super) fn get_timestamp(&self) -> u64 {
+        self.timestamp
     }
 
     #[inline]
@@ -85,11 +233,51 @@ impl LogEntry {
 
     #[inline]
     pub(super) fn get_value_offset(&self) -> SizeType {
-        Self::CHECKSUM_SIZE + Self::SIZE_SIZE * 2 + self.key_size()
+        self.header_size() + self.key_size()
+    }
+
+    #[inline]
+    fn header_size(&self) -> SizeType {
+        Self::header_size_of(self.version, self.expiry.is_some())
+    }
+
+    /// Size of everything but the key and value of an entry in format
+    /// `version`.
+    pub(super) fn header_size_of(version: u32, has_expiry: bool) -> SizeType {
+        let mut header_size = Self::CHECKSUM_SIZE + Self::SIZE_SIZE * 2;
+        if version != Self::LEGACY_VERSION {
+            header_size += Self::TIMESTAMP_SIZE + Self::FLAGS_SIZE + Self::CHECKSUM_SIZE;
+        }
+        if has_expiry {
+            header_size += Self::EXPIRY_SIZE;
+        }
+        header_size
+    }
+
+    #[inline]
+    pub(super) fn is_tombstone(&self) -> bool {
+        self.value.is_none()
+    }
+
+    #[inline]
+    pub(super) fn get_flags(&self) -> u8 {
+        self.flags
+    }
+
+    #[inline]
+    pub(super) fn get_expiry(&self) -> Option<u64> {
+        self.expiry
     }
 
     fn calculate_checksum(&self) -> u32 {
         let mut digest = Self::CRC32.digest();
+        if self.version != Self::LEGACY_VERSION {
+            digest.update(&self.timestamp.to_be_bytes());
+            digest.update(&[self.flags]);
+        }
+        if let Some(expiry) = self.expiry {
+            digest.update(&expiry.to_be_bytes());
+        }
         digest.update(&self.key_size().to_be_bytes());
         digest.update(&self.value_size().to_be_bytes());
         digest.update(&self.key);
@@ -99,7 +287,25 @@ impl LogEntry {
         digest.finalize()
     }
 
-    fn is_valid(&self) -> bool {
+    fn header_checksum(
+        timestamp: u64,
+        flags: u8,
+        expiry: Option<u64>,
+        key_size: SizeType,
+        value_size: SizeType,
+    ) -> u32 {
+        let mut digest = Self::CRC32.digest();
+        digest.update(&timestamp.to_be_bytes());
+        digest.update(&[flags]);
+        if let Some(expiry) = expiry {
+            digest.update(&expiry.to_be_bytes());
+        }
+        digest.update(&key_size.to_be_bytes());
+        digest.update(&value_size.to_be_bytes());
+        digest.finalize()
+    }
+
+    pub(super) fn is_valid(&self) -> bool {
         self.checksum == self.calculate_checksum()
     }
 }
@@ -107,14 +313,32 @@ impl LogEntry {
 impl Serialize for LogEntry {
     fn serialize<T: Write>(&self, buf: &mut T) -> Result<(), DBError> {
         let Self {
+            version,
             checksum,
+            timestamp,
+            flags,
+            expiry,
             key,
             value,
         } = self;
+        debug_assert_eq!(*version, Self::VERSION);
         buf.write_all(&checksum.to_be_bytes())?;
+        buf.write_all(&timestamp.to_be_bytes())?;
+        buf.write_all(&[*flags])?;
+        if let Some(expiry) = expiry {
+            buf.write_all(&expiry.to_be_bytes())?;
+        }
         buf.write_all(&self.key_size().to_be_bytes())?;
         buf.write_all(&self.value_size().to_be_bytes())?;
-        buf.write_all(&key)?;
+        let header_checksum = Self::header_checksum(
+            *timestamp,
+            *flags,
+            *expiry,
+            self.key_size(),
+            self.value_size(),
+        );
+        buf.write_all(&header_checksum.to_be_bytes())?;
+        buf.write_all(key)?;
         if let Some(value) = value {
             buf.write_all(value)?;
         }
@@ -122,39 +346,3 @@ impl Serialize for LogEntry {
         Ok(())
     }
 }
-
-impl Deserialize for LogEntry {
-    fn deserialize<T: Read>(buf: &mut T) -> Result<Self, DBError>
-    where
-        Self: Sized,
-    {
-        let mut checksum_buf = [0_u8; Self::CHECKSUM_SIZE as usize];
-        buf.read_exact(&mut checksum_buf)?;
-        let checksum = u32::from_be_bytes(checksum_buf);
-        let mut size_buf = [0_u8; Self::SIZE_SIZE as usize];
-        buf.read_exact(&mut size_buf)?;
-        let key_size = SizeType::from_be_bytes(size_buf);
-        buf.read_exact(&mut size_buf)?;
-        let value_size = SizeType::from_be_bytes(size_buf);
-        let mut key_buf = vec![0_u8; key_size as usize];
-        buf.read_exact(&mut key_buf)?;
-        let value = if value_size > 0 {
-            let mut value_buf = vec![0_u8; value_size as usize];
-            buf.read_exact(&mut value_buf)?;
-            Some(value_buf)
-        } else {
-            None
-        };
-
-        let entry = Self {
-            checksum,
-            key: key_buf,
-            value,
-        };
-        if entry.is_valid() {
-            Ok(entry)
-        } else {
-            Err(DBError::DataError("invalid checksum".to_string()))
-        }
-    }
-}
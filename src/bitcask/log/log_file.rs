@@ -1,30 +1,70 @@
 use std::{
     fs::{self, File, OpenOptions},
-    io::{BufReader, Seek, SeekFrom, Write},
-    path::PathBuf,
+    io::{Seek, SeekFrom, Write},
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    sync::Arc,
 };
 
 use crate::{
     bitcask::{
-        keydir::{KeyDir, KeyDirEntry},
+        opts::CorruptionPolicy,
+        recovery::{CorruptedFile, RecoveryReport},
         FileId, SizeType,
     },
     error::DBError,
 };
 
-use super::log_entry::{Deserialize, LogEntry, Serialize};
+use super::{
+    file_set,
+    hint_file::{HintEntry, HintFile, Replayer},
+    log_entry::{LogEntry, Serialize},
+    scanner::{self, Scanned, Scanner, Span},
+    sync_dir, Retired,
+};
 
+/// A data file. It starts with a header made of [`LogFile::MAGIC`] and the
+/// big-endian format version of its entries, except for files written before
+/// the header was introduced, which hold entries in
+/// [`LogEntry::LEGACY_VERSION`] only.
 #[derive(Debug)]
 pub(super) struct LogFile {
     file_id: FileId,
     path: PathBuf,
-    file: File,
+    /// Shared with the snapshots taken while this file is part of the log.
+    file: Arc<File>,
+    /// Format version of the entries in this file.
+    version: u32,
+    /// The newest timestamp of the entries in this file.
+    last_timestamp: u64,
+    /// Hints of the entries appended since this file was created. They are
+    /// written out by [`LogFile::write_hint`] once the file is sealed, after
+    /// which this is `None`.
+    hints: Option<Vec<HintEntry>>,
+    /// CRC32 of the contents of a file created by [`LogFile::new`], kept up
+    /// to date as entries are appended and written to its hint file.
+    checksum: u32,
+    /// Opened without write access, so that nothing on disk is changed.
+    read_only: bool,
+    /// Length of the prefix made of valid entries when the file was last
+    /// read or appended to. A reader goes on from there when the writer
+    /// appends more.
+    valid_sz: SizeType,
+    /// Number of tombstones in the valid prefix.
+    tombstones: u64,
 }
 
 impl LogFile {
     pub(super) const EXTENSION: &'static str = "tdb";
     pub(super) const MERGE_EXTENSION: &'static str = "merge";
-    pub(super) const MAX_FILE_SIZE: SizeType = 1_000_000; // 1MB
+    /// Extension of the files replaced by a merge that snapshots still use.
+    pub(super) const RETIRED_EXTENSION: &'static str = "retired";
+    pub(super) const HEADER_SIZE: SizeType = 8;
+    pub(super) const QUARANTINE_DIR: &'static str = "quarantine";
+    /// Extension of the copy that replaces a file once its damaged spans are
+    /// quarantined.
+    const REWRITE_EXTENSION: &'static str = "rewrite";
+    const MAGIC: [u8; 4] = *b"TDB\0";
 
     pub(super) fn new<T: Into<PathBuf>>(
         data_dir: T,
@@ -34,95 +74,438 @@ impl LogFile {
         let mut path: PathBuf = data_dir.into();
         path.push(file_id.to_string());
         path.set_extension(extension);
-        let file = OpenOptions::new()
+        let mut file = OpenOptions::new()
             .create(true)
             .read(true)
             .append(true)
             .open(&path)?;
+        if file.metadata()?.len() == 0 {
+            // In one write, so that readers never see half a header.
+            let mut header = Self::MAGIC.to_vec();
+            header.extend_from_slice(&LogEntry::VERSION.to_be_bytes());
+            file.write_all(&header)?;
+        }
+        // A hint left over from an older file with the same id would not
+        // describe this one.
+        let hint_path = path.with_extension(HintFile::EXTENSION);
+        if hint_path.exists() {
+            fs::remove_file(hint_path)?;
+        }
+        let checksum = file_set::checksum(&file, file.metadata()?.len())?;
 
         Ok(Self {
             file_id,
             path,
-            file,
+            file: Arc::new(file),
+            version: LogEntry::VERSION,
+            last_timestamp: 0,
+            hints: Some(vec![]),
+            checksum,
+            read_only: false,
+            valid_sz: Self::HEADER_SIZE,
+            tombstones: 0,
         })
     }
 
+    /// Opens an existing data file and replays it with `replayer`. A corrupt
+    /// tail is truncated if this is the `newest` file and handled according
+    /// to `policy` otherwise; either way it is recorded in `report`. A
+    /// `read_only` file is never written to: a corrupt tail is ignored
+    /// instead of truncated or quarantined, and no hint file is written.
     pub(super) fn open(
         file_id: FileId,
         path: PathBuf,
-        keydir: &mut KeyDir,
+        replayer: &mut Replayer,
+        newest: bool,
+        policy: CorruptionPolicy,
+        read_only: bool,
+        report: &mut RecoveryReport,
     ) -> Result<Self, DBError> {
-        let file = fs::OpenOptions::new().read(true).append(true).open(&path)?;
-        let file = Self {
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .append(!read_only)
+            .open(&path)?;
+        let version = Self::read_version(&file, &path)?;
+        let mut file = Self {
             file_id,
             path,
-            file,
+            file: Arc::new(file),
+            version,
+            last_timestamp: 0,
+            hints: None,
+            checksum: 0,
+            read_only,
+            valid_sz: 0,
+            tombstones: 0,
         };
-        file.populate_keydir(keydir)?;
+        file.populate_keydir(replayer, newest, policy, report)?;
 
         Ok(file)
     }
 
-    pub(super) fn append_entry(
+    /// Appends `entries` with a single write and returns the positions of
+    /// their values.
+    pub(super) fn append_entries(
         &mut self,
-        entry: LogEntry,
-        sync_on_put: bool,
-    ) -> Result<SizeType, DBError> {
-        let value_pos = self.file.seek(SeekFrom::End(0))? + entry.get_value_offset();
-        entry.serialize(&mut self.file)?;
-        if sync_on_put {
-            self.file.flush()?;
+        entries: &[LogEntry],
+        sync: bool,
+    ) -> Result<Vec<SizeType>, DBError> {
+        let mut offset = (&*self.file).seek(SeekFrom::End(0))?;
+        let mut buf = vec![];
+        let mut value_positions = Vec::with_capacity(entries.len());
+        for entry in entries {
+            entry.serialize(&mut buf)?;
+            value_positions.push(offset + entry.get_value_offset());
+            offset += entry.total_size();
+        }
+        (&*self.file).write_all(&buf)?;
+        self.checksum = file_set::extend_checksum(self.checksum, &buf);
+        self.valid_sz = offset;
+        if sync {
+            self.file.sync_data()?;
+        }
+        for (entry, value_pos) in entries.iter().zip(&value_positions) {
+            self.last_timestamp = self.last_timestamp.max(entry.get_timestamp());
+            self.tombstones += entry.is_tombstone() as u64;
+            if let Some(hints) = &mut self.hints {
+                hints.push(HintEntry::new(
+                    entry.get_key_ref().clone(),
+                    self.file_id,
+                    entry.value_size(),
+                    *value_pos,
+                    entry.get_timestamp(),
+                    entry.get_flags(),
+                    entry.get_expiry(),
+                ));
+            }
         }
 
-        Ok(value_pos)
+        Ok(value_positions)
     }
 
-    pub(super) fn change_extension(&mut self) -> Result<(), DBError> {
-        let old_path = self.path.clone();
-        self.path.set_extension(Self::EXTENSION);
-        fs::rename(old_path, self.path.clone())?;
+    /// Replays the entries appended since the file was opened or last
+    /// followed. The end of the `newest` file may be a write in progress, so
+    /// an invalid entry there is left for next time; in other files the
+    /// damaged spans are handled according to `policy` and recorded in
+    /// `report`.
+    pub(super) fn follow(
+        &mut self,
+        replayer: &mut Replayer,
+        newest: bool,
+        policy: CorruptionPolicy,
+        report: &mut RecoveryReport,
+    ) -> Result<(), DBError> {
+        if self.valid_sz == 0 {
+            // The header may not have been written when the file was opened.
+            self.version = Self::read_version(&self.file, &self.path)?;
+            self.valid_sz = self.data_offset();
+        }
+        let start = self.valid_sz;
+        let (mut hints, mut valid_sz, damaged) = self.scan(start, !newest)?;
+        if !newest && !damaged.is_empty() && self.recover(&damaged, false, policy, report)? {
+            (hints, valid_sz, _) = self.scan(start, false)?;
+        }
+        self.valid_sz = valid_sz;
+        self.replay(hints, replayer);
+
+        Ok(())
+    }
+
+    /// Writes the hints collected by [`LogFile::append_entries`] next to the
+    /// data file. Must only be called once no more entries will be appended.
+    /// Does nothing if the hint file has already been written.
+    pub(super) fn write_hint(&mut self) -> Result<(), DBError> {
+        if let Some(hints) = self.hints.take() {
+            HintFile::write(&self.hint_path(), self.checksum, &hints)?;
+        }
         Ok(())
     }
 
+    /// Returns another handle on this file for reading only, sharing the open
+    /// file.
+    pub(super) fn share(&self) -> Self {
+        Self {
+            file_id: self.file_id,
+            path: self.path.clone(),
+            file: self.file.clone(),
+            version: self.version,
+            last_timestamp: self.last_timestamp,
+            hints: None,
+            checksum: self.checksum,
+            read_only: true,
+            valid_sz: self.valid_sz,
+            tombstones: self.tombstones,
+        }
+    }
+
+    /// Returns the hints of this sealed file, from the hint file if there is a
+    /// valid one and from the data file otherwise.
+    pub(super) fn hints(&self) -> Result<Vec<HintEntry>, DBError> {
+        match HintFile::load(&self.hint_path()) {
+            Some(hints) => Ok(hints),
+            None => Ok(self.scan(self.data_offset(), true)?.0),
+        }
+    }
+
+    /// Updates the path after the file has been renamed on disk.
+    pub(super) fn set_extension(&mut self, extension: &'static str) {
+        self.path.set_extension(extension);
+    }
+
+    /// Gives the file the id `file_id`, renaming it and updating the hints
+    /// that have not been written yet.
+    pub(super) fn renumber(&mut self, file_id: FileId) -> Result<(), DBError> {
+        let mut path = self.path.with_file_name(file_id.to_string());
+        if let Some(extension) = self.path.extension() {
+            path.set_extension(extension);
+        }
+        fs::rename(&self.path, &path)?;
+        for hint in self.hints.iter_mut().flatten() {
+            hint.set_file_id(file_id);
+        }
+        self.file_id = file_id;
+        self.path = path;
+        Ok(())
+    }
+
+    #[inline]
+    pub(super) fn sync(&self) -> Result<(), DBError> {
+        self.file.sync_all()?;
+        Ok(())
+    }
+
+    #[inline]
+    pub(super) fn get_path(&self) -> &PathBuf {
+        &self.path
+    }
+
     #[inline]
     pub(super) fn get_file_id(&self) -> FileId {
         self.file_id
     }
 
     #[inline]
-    pub(super) fn get_file(&self) -> &File {
+    pub(super) fn get_file(&self) -> &Arc<File> {
         &self.file
     }
 
     #[inline]
-    pub(super) fn get_file_mut(&mut self) -> &mut File {
-        &mut self.file
+    pub(super) fn get_valid_size(&self) -> SizeType {
+        self.valid_sz
     }
 
-    fn populate_keydir(&self, keydir: &mut KeyDir) -> Result<(), DBError> {
-        let file_sz = self.file.metadata()?.len();
-        let mut buf_reader = BufReader::new(&self.file);
-        let mut cursor = 0_u64;
-        buf_reader.seek(SeekFrom::Start(cursor))?;
-        loop {
-            if cursor >= file_sz {
-                break;
+    #[inline]
+    pub(super) fn get_tombstones(&self) -> u64 {
+        self.tombstones
+    }
+
+    #[inline]
+    pub(super) fn get_version(&self) -> u32 {
+        self.version
+    }
+
+    #[inline]
+    pub(super) fn get_last_timestamp(&self) -> u64 {
+        self.last_timestamp
+    }
+
+    /// Moves this file aside after a merge replaced it if a snapshot still
+    /// reads from it, leaving it to `retired` to delete later.
+    pub(super) fn retire_if_shared(self, retired: &Retired) -> Result<(), DBError> {
+        if Arc::strong_count(&self.file) > 1 {
+            let retired_path = self.path.with_extension(Self::RETIRED_EXTENSION);
+            fs::rename(&self.path, &retired_path)?;
+            retired.add(retired_path, Arc::downgrade(&self.file));
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn hint_path(&self) -> PathBuf {
+        self.path.with_extension(HintFile::EXTENSION)
+    }
+
+    /// Replays the hint file if there is a valid one, and the data file itself
+    /// otherwise. In the latter case the missing hint file is written so that
+    /// the next start is fast, unless the file is read-only.
+    fn populate_keydir(
+        &mut self,
+        replayer: &mut Replayer,
+        newest: bool,
+        policy: CorruptionPolicy,
+        report: &mut RecoveryReport,
+    ) -> Result<(), DBError> {
+        let hints = match HintFile::load(&self.hint_path()) {
+            Some(hints) => {
+                self.valid_sz = self.file.metadata()?.len();
+                hints
             }
-            let log_entry = LogEntry::deserialize(&mut buf_reader)?;
-            let log_entry_size = log_entry.total_size();
-            if log_entry.is_tombstone() {
-                keydir.delete(log_entry.get_key_ref());
-            } else {
-                let keydir_entry = KeyDirEntry::new(
-                    self.file_id,
-                    log_entry.value_size(),
-                    cursor + log_entry.get_value_offset(),
-                );
-                keydir.put(log_entry.get_key(), keydir_entry);
+            None => {
+                let (mut hints, mut valid_sz, damaged) = self.scan(self.data_offset(), !newest)?;
+                if !damaged.is_empty() && self.recover(&damaged, newest, policy, report)? {
+                    (hints, valid_sz, _) = self.scan(self.data_offset(), false)?;
+                }
+                if !self.read_only {
+                    let checksum = file_set::checksum(&self.file, self.file.metadata()?.len())?;
+                    HintFile::write(&self.hint_path(), checksum, &hints)?;
+                }
+                self.valid_sz = valid_sz;
+                hints
             }
-            cursor += log_entry_size;
-        }
+        };
+        self.replay(hints, replayer);
 
         Ok(())
     }
+
+    fn replay(&mut self, hints: Vec<HintEntry>, replayer: &mut Replayer) {
+        for hint in hints {
+            self.last_timestamp = self.last_timestamp.max(hint.get_timestamp());
+            self.tombstones += hint.is_tombstone() as u64;
+            replayer.replay(hint);
+        }
+    }
+
+    /// Reads the format version from the header. Files without a header are
+    /// in the legacy format.
+    pub(super) fn read_version(file: &File, path: &Path) -> Result<u32, DBError> {
+        if file.metadata()?.len() < Self::HEADER_SIZE {
+            return Ok(LogEntry::LEGACY_VERSION);
+        }
+        let mut header = [0_u8; Self::HEADER_SIZE as usize];
+        file.read_exact_at(&mut header, 0)?;
+        let (magic, version) = header.split_at(Self::MAGIC.len());
+        if magic != Self::MAGIC {
+            return Ok(LogEntry::LEGACY_VERSION);
+        }
+        let version = u32::from_be_bytes(version.try_into().unwrap());
+        if version != LogEntry::VERSION {
+            return Err(DBError::VersionError(format!(
+                "{} has format version {}, but only version {} is supported",
+                path.display(),
+                version,
+                LogEntry::VERSION
+            )));
+        }
+
+        Ok(version)
+    }
+
+    /// Offset of the first entry.
+    #[inline]
+    pub(super) fn data_offset(&self) -> SizeType {
+        if self.version == LogEntry::LEGACY_VERSION {
+            0
+        } else {
+            Self::HEADER_SIZE
+        }
+    }
+
+    /// Deals with the `damaged` spans, which hold no valid entry. In the
+    /// `newest` file there is only one, at the end, which is truncated.
+    /// Returns whether the file was rewritten without them, after which it
+    /// must be scanned again.
+    fn recover(
+        &mut self,
+        damaged: &[Span],
+        newest: bool,
+        policy: CorruptionPolicy,
+        report: &mut RecoveryReport,
+    ) -> Result<bool, DBError> {
+        if newest {
+            let (offset, len) = damaged[0];
+            if !self.read_only {
+                self.file.set_len(offset)?;
+                self.file.sync_all()?;
+            }
+            report.truncated_bytes += len;
+            return Ok(false);
+        }
+
+        let quarantine_paths = match policy {
+            CorruptionPolicy::Fail => {
+                return Err(DBError::DataError(format!(
+                    "invalid entry in {} at offset {}",
+                    self.path.display(),
+                    damaged[0].0
+                )))
+            }
+            CorruptionPolicy::Quarantine if !self.read_only => {
+                self.quarantine(damaged)?.into_iter().map(Some).collect()
+            }
+            CorruptionPolicy::Skip | CorruptionPolicy::Quarantine => vec![None; damaged.len()],
+        };
+        let rewritten = quarantine_paths.iter().any(Option::is_some);
+        for (&(offset, len), quarantine_path) in damaged.iter().zip(quarantine_paths) {
+            report.corrupted_files.push(CorruptedFile {
+                file_id: self.file_id,
+                offset,
+                ignored_bytes: len,
+                quarantine_path,
+            });
+        }
+
+        Ok(rewritten)
+    }
+
+    /// Moves the `damaged` spans into the quarantine directory and replaces
+    /// the file with a copy of the rest. Returns where each span went.
+    fn quarantine(&mut self, damaged: &[Span]) -> Result<Vec<PathBuf>, DBError> {
+        let quarantine_dir = self.path.with_file_name(Self::QUARANTINE_DIR);
+        fs::create_dir_all(&quarantine_dir)?;
+        let mut quarantine_paths = Vec::with_capacity(damaged.len());
+        for &(offset, len) in damaged {
+            let quarantine_path = quarantine_dir.join(format!("{}-{}.bad", self.file_id, offset));
+            scanner::copy_span(&self.file, (offset, len), &quarantine_path)?;
+            quarantine_paths.push(quarantine_path);
+        }
+        sync_dir(&quarantine_dir)?;
+
+        let tmp_path = self.path.with_extension(Self::REWRITE_EXTENSION);
+        scanner::copy_without(&self.file, damaged, &tmp_path)?;
+        fs::rename(&tmp_path, &self.path)?;
+        sync_dir(self.path.parent().unwrap())?;
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(&self.path)?;
+        self.file = Arc::new(file);
+
+        Ok(quarantine_paths)
+    }
+
+    /// Reads the entries of the data file from offset `start` and returns
+    /// their hints, the end of the last of them, and the damaged spans
+    /// between and after them as offsets and lengths. Unless `resync` is
+    /// set, reading stops at the first entry that is truncated or fails its
+    /// checksum, which starts the only span.
+    fn scan(
+        &self,
+        start: SizeType,
+        resync: bool,
+    ) -> Result<(Vec<HintEntry>, SizeType, Vec<Span>), DBError> {
+        let file_sz = self.file.metadata()?.len();
+        let mut valid_sz = start;
+        let mut hints = vec![];
+        let mut damaged = vec![];
+        for scanned in Scanner::new(&self.file, self.version, start, file_sz, resync) {
+            match scanned? {
+                Scanned::Entry { offset, entry } => {
+                    valid_sz = offset + entry.total_size();
+                    hints.push(HintEntry::new(
+                        entry.get_key_ref().clone(),
+                        self.file_id,
+                        entry.value_size(),
+                        offset + entry.get_value_offset(),
+                        entry.get_timestamp(),
+                        entry.get_flags(),
+                        entry.get_expiry(),
+                    ));
+                }
+                Scanned::Damaged { offset, len, .. } => damaged.push((offset, len)),
+            }
+        }
+
+        Ok((hints, valid_sz, damaged))
+    }
 }
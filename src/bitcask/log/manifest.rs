@@ -0,0 +1,190 @@
+use std::{
+    fs,
+    io::{ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use crc::{Crc, CRC_32_CKSUM};
+
+use crate::{
+    bitcask::{FileId, SizeType},
+    error::DBError,
+};
+
+use super::{
+    hint_file::HintFile,
+    log_entry::{Deserialize, Serialize},
+    log_file::LogFile,
+    sync_dir,
+};
+
+/// Records the outcome of a merge. Once the manifest is on disk the merge is
+/// committed: the files in `merged` replace the files in `replaced`, and
+/// [`MergeManifest::apply`] can be run again after a crash until it finishes.
+pub(super) struct MergeManifest {
+    replaced: Vec<FileId>,
+    merged: Vec<FileId>,
+}
+
+impl MergeManifest {
+    const FILE_NAME: &'static str = "MERGE";
+    const TMP_FILE_NAME: &'static str = "MERGE.tmp";
+    const CHECKSUM_SIZE: usize = 4;
+    const SIZE_SIZE: usize = SizeType::BITS as usize / 8;
+    const CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_CKSUM);
+
+    pub(super) fn new(replaced: Vec<FileId>, merged: Vec<FileId>) -> Self {
+        Self { replaced, merged }
+    }
+
+    /// Atomically writes the manifest into `data_dir`.
+    pub(super) fn write(&self, data_dir: &Path) -> Result<(), DBError> {
+        let mut buf = vec![];
+        self.serialize(&mut buf)?;
+        let checksum = Self::CRC32.checksum(&buf);
+        buf.write_all(&checksum.to_be_bytes())?;
+
+        let tmp_path = data_dir.join(Self::TMP_FILE_NAME);
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        fs::rename(tmp_path, data_dir.join(Self::FILE_NAME))?;
+        sync_dir(data_dir)
+    }
+
+    pub(super) fn load(data_dir: &Path) -> Result<Option<Self>, DBError> {
+        let buf = match fs::read(data_dir.join(Self::FILE_NAME)) {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        if buf.len() < Self::CHECKSUM_SIZE {
+            return Err(DBError::DataError("truncated merge manifest".to_string()));
+        }
+        let (mut body, checksum) = buf.split_at(buf.len() - Self::CHECKSUM_SIZE);
+        if Self::CRC32.checksum(body).to_be_bytes() != checksum {
+            return Err(DBError::DataError(
+                "invalid merge manifest checksum".to_string(),
+            ));
+        }
+
+        Self::deserialize(&mut body).map(Some)
+    }
+
+    /// Renames the merged files into place, deletes the replaced files and
+    /// finally removes the manifest. Every step may already have been done.
+    pub(super) fn apply(&self, data_dir: &Path) -> Result<(), DBError> {
+        for file_id in &self.merged {
+            let merge_path = Self::data_path(data_dir, *file_id, LogFile::MERGE_EXTENSION);
+            if merge_path.exists() {
+                let path = merge_path.with_extension(LogFile::EXTENSION);
+                fs::rename(merge_path, path)?;
+            }
+        }
+        for file_id in &self.replaced {
+            let path = Self::data_path(data_dir, *file_id, LogFile::EXTENSION);
+            remove_if_exists(&path.with_extension(HintFile::EXTENSION))?;
+            remove_if_exists(&path)?;
+        }
+        sync_dir(data_dir)?;
+        remove_if_exists(&data_dir.join(Self::FILE_NAME))?;
+        sync_dir(data_dir)
+    }
+
+    /// Returns the data files among `files` as they are once the manifest is
+    /// applied, without changing anything on disk. The replaced files are
+    /// left out and the merged files that have not been renamed yet are read
+    /// from where they are.
+    pub(super) fn resolve(&self, data_dir: &Path, files: Vec<PathBuf>) -> Vec<PathBuf> {
+        let mut files: Vec<PathBuf> = files
+            .into_iter()
+            .filter(|path| {
+                let file_id = path
+                    .file_stem()
+                    .and_then(|file_stem| file_stem.to_str())
+                    .and_then(|file_stem| file_stem.parse::<FileId>().ok());
+                !file_id.is_some_and(|file_id| self.replaced.contains(&file_id))
+            })
+            .collect();
+        for file_id in &self.merged {
+            let merge_path = Self::data_path(data_dir, *file_id, LogFile::MERGE_EXTENSION);
+            if merge_path.exists() {
+                files.push(merge_path);
+            }
+        }
+        files
+    }
+
+    /// Removes the manifest of a merge that is being rolled back.
+    pub(super) fn remove(data_dir: &Path) -> Result<(), DBError> {
+        remove_if_exists(&data_dir.join(Self::FILE_NAME))?;
+        sync_dir(data_dir)
+    }
+
+    /// Deletes the output of a merge that never reached its manifest, and
+    /// the replaced files that snapshots of an earlier run kept around.
+    pub(super) fn discard_unfinished(data_dir: &Path) -> Result<(), DBError> {
+        for path in fs::read_dir(data_dir)? {
+            let path = path?.path();
+            if path.extension() == Some(LogFile::MERGE_EXTENSION.as_ref()) {
+                remove_if_exists(&path.with_extension(HintFile::EXTENSION))?;
+                fs::remove_file(path)?;
+            } else if path.extension() == Some(LogFile::RETIRED_EXTENSION.as_ref()) {
+                fs::remove_file(path)?;
+            }
+        }
+        remove_if_exists(&data_dir.join(Self::TMP_FILE_NAME))
+    }
+
+    fn data_path(data_dir: &Path, file_id: FileId, extension: &str) -> PathBuf {
+        let mut path = data_dir.join(file_id.to_string());
+        path.set_extension(extension);
+        path
+    }
+
+    fn serialize_ids<T: Write>(ids: &[FileId], buf: &mut T) -> Result<(), DBError> {
+        buf.write_all(&(ids.len() as SizeType).to_be_bytes())?;
+        for id in ids {
+            buf.write_all(&(*id as SizeType).to_be_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn deserialize_ids<T: Read>(buf: &mut T) -> Result<Vec<FileId>, DBError> {
+        let mut size_buf = [0_u8; Self::SIZE_SIZE];
+        buf.read_exact(&mut size_buf)?;
+        let len = SizeType::from_be_bytes(size_buf);
+        (0..len)
+            .map(|_| {
+                buf.read_exact(&mut size_buf)?;
+                Ok(SizeType::from_be_bytes(size_buf) as FileId)
+            })
+            .collect()
+    }
+}
+
+impl Serialize for MergeManifest {
+    fn serialize<T: Write>(&self, buf: &mut T) -> Result<(), DBError> {
+        Self::serialize_ids(&self.replaced, buf)?;
+        Self::serialize_ids(&self.merged, buf)
+    }
+}
+
+impl Deserialize for MergeManifest {
+    fn deserialize<T: Read>(buf: &mut T) -> Result<Self, DBError>
+    where
+        Self: Sized,
+    {
+        let replaced = Self::deserialize_ids(buf)?;
+        let merged = Self::deserialize_ids(buf)?;
+        Ok(Self { replaced, merged })
+    }
+}
+
+fn remove_if_exists(path: &Path) -> Result<(), DBError> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
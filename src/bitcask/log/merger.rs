@@ -0,0 +1,368 @@
+use std::{
+    collections::BTreeMap,
+    fs, mem,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    bitcask::{
+        keydir::{KeyDir, KeyDirEntry},
+        now_micros, FileId, Key, SizeType, Value,
+    },
+    error::DBError,
+};
+
+use super::{
+    file_set, hint_file::HintFile, log_entry::LogEntry, log_file::LogFile, manifest::MergeManifest,
+};
+
+/// A merge of some data files, started by
+/// [`Log::start_merge`](super::Log::start_merge). The files and the keydir
+/// are taken as they were then, so the live values can be copied by
+/// [`Merger::write`] while writes go on. What was written
+/// meanwhile is caught up with by [`Merger::reconcile`] before the merge is
+/// committed.
+pub(crate) struct Merger {
+    data_dir: PathBuf,
+    /// Read-only handles on the files to merge.
+    sources: BTreeMap<FileId, LogFile>,
+    /// Files that the merge output replaces besides the sources.
+    replaced: Vec<FileId>,
+    /// The oldest data file that is not merged, if any. Deletions are only
+    /// copied if they may shadow a value in it or a later file.
+    oldest_kept: Option<FileId>,
+    /// The keydir when the merge started, which shares its map with the
+    /// keydir of the storage. Let go of once [`Merger::write`] is done with
+    /// it, so the versions of the map that writes replace meanwhile are not
+    /// kept any longer.
+    keydir: KeyDir,
+    /// Shared with the log, which hands out ids to new active files
+    /// meanwhile.
+    next_file_id: Arc<AtomicUsize>,
+    max_file_size: SizeType,
+    merged_files: Vec<LogFile>,
+    cur_merged_file_sz: SizeType,
+    /// Every key written to the merge output, with its keydir entry when the
+    /// merge started and the one in the output, which is `None` for a
+    /// deletion.
+    written: Vec<(Key, Option<KeyDirEntry>, Option<KeyDirEntry>)>,
+    rate_limit: Option<RateLimit>,
+}
+
+impl Merger {
+    /// How long [`Merger::write`] sleeps at most before checking whether to
+    /// stop.
+    const MAX_SLEEP: Duration = Duration::from_millis(100);
+
+    pub(super) fn new(
+        data_dir: PathBuf,
+        sources: BTreeMap<FileId, LogFile>,
+        oldest_kept: Option<FileId>,
+        keydir: KeyDir,
+        next_file_id: Arc<AtomicUsize>,
+        max_file_size: SizeType,
+    ) -> Self {
+        Self {
+            data_dir,
+            sources,
+            replaced: vec![],
+            oldest_kept,
+            keydir,
+            next_file_id,
+            max_file_size,
+            merged_files: vec![],
+            cur_merged_file_sz: 0,
+            written: vec![],
+            rate_limit: None,
+        }
+    }
+
+    /// Makes [`Merger::write`] read and write no more than `bytes_per_sec`
+    /// on average.
+    pub(crate) fn limit_rate(&mut self, bytes_per_sec: u64) {
+        self.rate_limit = Some(RateLimit::new(bytes_per_sec));
+    }
+
+    /// Copies every live value in the merged files that has not expired into
+    /// the merge output, along with the deletions that may shadow values in
+    /// older files that are not merged. Gives up and returns `false` as soon
+    /// as `stop` returns `true`.
+    pub(crate) fn write<F: Fn() -> bool>(&mut self, stop: F) -> Result<bool, DBError> {
+        let keydir = mem::take(&mut self.keydir);
+        for (key, entry) in keydir.iter() {
+            if !self.sources.contains_key(&entry.file_id) {
+                continue;
+            }
+            if !self.wait_turn(&stop) {
+                return Ok(false);
+            }
+            if entry.is_expired() {
+                if self.shadows(entry.file_id) {
+                    self.delete(key, entry.timestamp)?;
+                }
+                self.written.push((key.clone(), Some(entry.clone()), None));
+                continue;
+            }
+            let value = file_set::read_value(self.sources[&entry.file_id].get_file(), entry)?;
+            let merged_entry = self.put(key, value, entry.timestamp, entry.expiry)?;
+            self.written
+                .push((key.clone(), Some(entry.clone()), Some(merged_entry)));
+        }
+
+        let mut deletions = BTreeMap::new();
+        for source in self.sources.values() {
+            if !self.shadows(source.get_file_id()) {
+                continue;
+            }
+            for hint in source.hints()? {
+                if hint.is_deletion() {
+                    let timestamp = hint.get_timestamp();
+                    deletions.insert(hint.get_key(), timestamp);
+                }
+            }
+        }
+        for (key, timestamp) in deletions {
+            if !self.wait_turn(&stop) {
+                return Ok(false);
+            }
+            // A key written since has nothing left to shadow.
+            if keydir.get(&key).is_none() {
+                self.delete(&key, timestamp)?;
+                self.written.push((key, None, None));
+            }
+        }
+
+        // Syncing here keeps the commit, which holds up writers, short.
+        for log_file in &self.merged_files {
+            log_file.sync()?;
+        }
+        Ok(true)
+    }
+
+    /// Brings the merge output up to date with `keydir`, which is what the
+    /// keydir of the merge became while [`Merger::write`] ran, and returns
+    /// the changes to make to it once the merge is committed. A key written
+    /// since the merge started is copied again from where `read` finds it,
+    /// so that the merge output, which replays after the files written to
+    /// meanwhile, holds its latest value. The merge output is then
+    /// renumbered to come after those files.
+    pub(crate) fn reconcile<F: Fn(&KeyDirEntry) -> Result<Value, DBError>>(
+        &mut self,
+        keydir: &KeyDir,
+        read: F,
+    ) -> Result<Vec<(Key, Option<KeyDirEntry>)>, DBError> {
+        self.rate_limit = None;
+        let mut updates = Vec::with_capacity(self.written.len());
+        for (key, then, merged) in mem::take(&mut self.written) {
+            let now = keydir.get(&key);
+            let unchanged = match (now, &then) {
+                (Some(now), Some(then)) => {
+                    now.file_id == then.file_id && now.value_pos == then.value_pos
+                }
+                (now, then) => now.is_none() && then.is_none(),
+            };
+            if unchanged {
+                updates.push((key, merged));
+                continue;
+            }
+            match now {
+                Some(entry) => {
+                    let value = read(entry)?;
+                    let merged_entry = self.put(&key, value, entry.timestamp, entry.expiry)?;
+                    updates.push((key, Some(merged_entry)));
+                }
+                None if merged.is_none() => {}
+                None => self.delete(&key, now_micros())?,
+            }
+        }
+        self.renumber(&mut updates)?;
+        Ok(updates)
+    }
+
+    /// Makes the merge output durable and returns the manifest that commits
+    /// it.
+    pub(super) fn seal(&mut self) -> Result<MergeManifest, DBError> {
+        for log_file in &mut self.merged_files {
+            log_file.sync()?;
+            log_file.write_hint()?;
+        }
+        let mut replaced: Vec<FileId> = self.sources.keys().copied().collect();
+        replaced.extend(&self.replaced);
+        Ok(MergeManifest::new(replaced, self.merged_file_ids()))
+    }
+
+    /// Adds `file_id` to the files that the merge output replaces.
+    pub(super) fn replace(&mut self, file_id: FileId) {
+        self.replaced.push(file_id);
+    }
+
+    /// The id of the newest file of the merge output, if there is any.
+    pub(super) fn last_merged_file_id(&self) -> Option<FileId> {
+        self.merged_files.last().map(|f| f.get_file_id())
+    }
+
+    /// Returns the ids of the replaced files and the merge output, letting go
+    /// of the handles on the replaced files.
+    pub(super) fn into_files(self) -> (Vec<FileId>, Vec<LogFile>) {
+        let mut replaced: Vec<FileId> = self.sources.into_keys().collect();
+        replaced.extend(self.replaced);
+        (replaced, self.merged_files)
+    }
+
+    /// Deletes the merge output.
+    pub(super) fn discard(self) -> Result<(), DBError> {
+        for log_file in self.merged_files {
+            let path = log_file.get_path().clone();
+            drop(log_file);
+            fs::remove_file(&path)?;
+            let hint_path = path.with_extension(HintFile::EXTENSION);
+            if hint_path.exists() {
+                fs::remove_file(hint_path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sleeps until the rate limit lets the merge go on, and returns whether
+    /// it should, which it should not once `stop` returns `true`.
+    fn wait_turn<F: Fn() -> bool>(&self, stop: &F) -> bool {
+        loop {
+            if stop() {
+                return false;
+            }
+            match self.rate_limit.as_ref().and_then(RateLimit::wait) {
+                Some(wait) => thread::sleep(wait.min(Self::MAX_SLEEP)),
+                None => return true,
+            }
+        }
+    }
+
+    /// Gives the merge output the next file ids and renames it accordingly,
+    /// along with the keydir entries in `updates`. Its ids were taken when
+    /// it was created, so files created by the log afterwards may be
+    /// numbered between them, and a batch written to those would be split
+    /// around a merged file.
+    fn renumber(&mut self, updates: &mut [(Key, Option<KeyDirEntry>)]) -> Result<(), DBError> {
+        let mut file_ids = BTreeMap::new();
+        for log_file in &mut self.merged_files {
+            let file_id = self.next_file_id.fetch_add(1, Ordering::Relaxed);
+            file_ids.insert(log_file.get_file_id(), file_id);
+            log_file.renumber(file_id)?;
+        }
+        for entry in updates.iter_mut().filter_map(|(_, entry)| entry.as_mut()) {
+            entry.file_id = file_ids[&entry.file_id];
+        }
+        Ok(())
+    }
+
+    fn merged_file_ids(&self) -> Vec<FileId> {
+        self.merged_files.iter().map(|f| f.get_file_id()).collect()
+    }
+
+    /// Whether a deletion in `file_id` may shadow a value in a file that is
+    /// not merged.
+    fn shadows(&self, file_id: FileId) -> bool {
+        self.oldest_kept.is_some_and(|oldest| oldest < file_id)
+    }
+
+    /// Copies a live value into the merge output, keeping its timestamp and
+    /// expiry.
+    fn put(
+        &mut self,
+        key: &Key,
+        value: Value,
+        timestamp: u64,
+        expiry: Option<u64>,
+    ) -> Result<KeyDirEntry, DBError> {
+        let value_sz = value.len() as SizeType;
+        let mut entry = LogEntry::new_live_entry(key.clone(), value, timestamp);
+        if let Some(expiry) = expiry {
+            entry = entry.with_expiry(expiry);
+        }
+        let (file_id, value_pos) = self.append(&entry, value_sz)?;
+
+        Ok(KeyDirEntry::new(
+            file_id,
+            entry.value_size(),
+            value_pos,
+            entry.get_timestamp(),
+            entry.get_expiry(),
+        ))
+    }
+
+    /// Copies a deletion into the merge output as a tombstone.
+    fn delete(&mut self, key: &Key, timestamp: u64) -> Result<(), DBError> {
+        let entry = LogEntry::new_tombstone_entry(key.clone(), timestamp);
+        self.append(&entry, 0)?;
+        Ok(())
+    }
+
+    /// Appends `entry`, whose value of `read_sz` bytes was read for it, to
+    /// the merge output, starting a new file when the current one would grow
+    /// too large, and returns where its value is.
+    fn append(
+        &mut self,
+        entry: &LogEntry,
+        read_sz: SizeType,
+    ) -> Result<(FileId, SizeType), DBError> {
+        let entry_sz = entry.total_size();
+        if self.merged_files.is_empty()
+            || (self.cur_merged_file_sz > LogFile::HEADER_SIZE
+                && self.cur_merged_file_sz + entry_sz > self.max_file_size)
+        {
+            self.create_new_merge_file()?;
+        }
+        self.cur_merged_file_sz += entry_sz;
+        let log_file = self.merged_files.last_mut().unwrap();
+        let value_pos = log_file.append_entries(std::slice::from_ref(entry), false)?[0];
+        if let Some(rate_limit) = &mut self.rate_limit {
+            rate_limit.consume(read_sz + entry_sz);
+        }
+        Ok((log_file.get_file_id(), value_pos))
+    }
+
+    /// Starts a new file of merge output. Hint files are only written once
+    /// the output is renumbered, when it is sealed.
+    fn create_new_merge_file(&mut self) -> Result<(), DBError> {
+        let file_id = self.next_file_id.fetch_add(1, Ordering::Relaxed);
+        let log_file = LogFile::new(&self.data_dir, file_id, LogFile::MERGE_EXTENSION)?;
+        self.merged_files.push(log_file);
+        self.cur_merged_file_sz = LogFile::HEADER_SIZE;
+
+        Ok(())
+    }
+}
+
+/// Tells how long to wait to keep the average rate at which bytes are
+/// consumed since it was created under a limit.
+struct RateLimit {
+    bytes_per_sec: u64,
+    start: Instant,
+    bytes: u64,
+}
+
+impl RateLimit {
+    fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            bytes_per_sec: bytes_per_sec.max(1),
+            start: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    fn consume(&mut self, bytes: u64) {
+        self.bytes += bytes;
+    }
+
+    fn wait(&self) -> Option<Duration> {
+        let due = Duration::from_secs_f64(self.bytes as f64 / self.bytes_per_sec as f64);
+        due.checked_sub(self.start.elapsed())
+            .filter(|wait| !wait.is_zero())
+    }
+}
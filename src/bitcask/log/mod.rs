@@ -1,9 +1,14 @@
 use std::{
-    cell::Cell,
+    collections::{BTreeMap, BTreeSet},
     ffi::OsStr,
-    fs,
-    io::{BufReader, Read, Seek, SeekFrom, Write},
-    path::PathBuf,
+    fs::{self, File},
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
     vec,
 };
 
@@ -11,132 +16,493 @@ use log_entry::LogEntry;
 
 use crate::error::DBError;
 
-use self::log_file::LogFile;
+pub(super) use self::{
+    checkpoint::Checkpoint, file_set::FileSet, merger::Merger, verifier::Verifier,
+};
+use self::{
+    checkpoint::CheckpointFile,
+    file_set::Retired,
+    hint_file::{HintEntry, Replayer},
+    log_file::LogFile,
+    manifest::MergeManifest,
+    verifier::VerifierFile,
+};
 use super::{
-    keydir::{KeyDir, KeyDirEntry},
+    batch::BatchOp,
+    keydir::{KeyDir, KeyDirEntry, KeyDirStats},
+    now_micros,
+    opts::{CorruptionPolicy, Opts, SyncMode},
+    recovery::{RecoveryReport, RepairReport},
+    stats::FileStats,
     FileId, Key, SizeType, Value,
 };
 
+mod checkpoint;
+mod file_set;
+mod hint_file;
 mod log_entry;
 mod log_file;
+mod manifest;
+mod merger;
+mod repair;
+mod scanner;
+mod verifier;
 
 pub(super) struct Log {
-    files: Vec<LogFile>,
+    files: BTreeMap<FileId, LogFile>,
     data_dir: PathBuf,
     cur_file_sz: SizeType,
-    merged_files: Cell<Vec<LogFile>>,
-    cur_merged_file_sz: SizeType,
+    /// The id of the next data file, shared with the merge in progress,
+    /// which creates files while the log is written to.
+    next_file_id: Arc<AtomicUsize>,
+    /// Whether a merge is in progress. Only one runs at a time.
+    merging: bool,
+    /// The newest timestamp handed out so far. Timestamps are strictly
+    /// increasing even if the system clock goes backwards.
+    last_timestamp: u64,
+    /// Repairs made to the data files while opening them.
+    recovery_report: RecoveryReport,
+    sync_mode: SyncMode,
+    /// Bytes appended to the active file since it was last synced.
+    unsynced_bytes: AtomicU64,
+    /// Replaced files that snapshots still read from.
+    retired: Arc<Retired>,
+    max_file_size: SizeType,
+    max_file_age: Option<Duration>,
+    /// Thresholds above which [`Log::files_to_merge`] picks a file.
+    merge_dead_ratio: Option<f64>,
+    merge_dead_bytes: Option<u64>,
+    /// When the first entry was appended to the active file.
+    cur_file_first_write: Option<Instant>,
+    corruption_policy: CorruptionPolicy,
+    /// Entries of a batch a reader has only seen the start of so far.
+    pending_batch: Option<Vec<HintEntry>>,
+    /// Whether this is a reader, which has no active file.
+    read_only: bool,
 }
 
 impl Log {
     pub(super) fn from_disk<T: Into<PathBuf>>(
         data_dir: T,
         keydir: &mut KeyDir,
+        opts: &Opts,
     ) -> Result<Self, DBError> {
         let data_dir = data_dir.into();
+        let sync_mode = opts.get_sync_mode();
 
-        let files = fs::read_dir(&data_dir)?
-            .filter_map(|path| {
-                path.ok().map(|path| path.path()).filter(|path| {
-                    path.is_file() && path.extension() == Some(OsStr::new(LogFile::EXTENSION))
-                })
-            })
-            .collect();
-        let mut files = Self::to_log_files(files, keydir)?;
+        let read_only = !opts.is_mutable();
+        let manifest = MergeManifest::load(&data_dir)?;
+        // Roll a committed merge forward and an unfinished one back before
+        // looking at the data files. Readers leave both to the next writer.
+        if !read_only {
+            if let Some(manifest) = &manifest {
+                manifest.apply(&data_dir)?;
+            }
+            MergeManifest::discard_unfinished(&data_dir)?;
+        }
+
+        let files = Self::data_files(&data_dir, manifest.as_ref().filter(|_| read_only))?;
+        let mut recovery_report = RecoveryReport::default();
+        let policy = opts.get_corruption_policy();
+        let mut replayer = Replayer::new(keydir);
+        let mut files = Self::to_log_files(
+            files,
+            &mut replayer,
+            policy,
+            read_only,
+            &mut recovery_report,
+        )?;
+        // A reader may see a batch that the writer has not finished yet.
+        let pending_batch = replayer.into_batch().filter(|_| read_only);
 
-        let next_file_id = if files.len() == 0 {
-            0
-        } else {
-            files.last().unwrap().get_file_id() + 1
+        let next_file_id = match files.last_key_value() {
+            Some((file_id, _)) => file_id + 1,
+            None => 0,
         };
-        let cur_file = LogFile::new(data_dir.clone(), next_file_id, LogFile::EXTENSION)?;
-        files.push(cur_file);
+        let last_timestamp = files
+            .values()
+            .map(|f| f.get_last_timestamp())
+            .max()
+            .unwrap_or(0);
+        // Readers never append, so they have no active file.
+        if !read_only {
+            let cur_file = Self::new_active_file(&data_dir, next_file_id, sync_mode)?;
+            files.insert(next_file_id, cur_file);
+        }
 
         Ok(Self {
             files,
             data_dir,
-            cur_file_sz: 0,
-            merged_files: Cell::new(vec![]),
-            cur_merged_file_sz: 0,
+            cur_file_sz: LogFile::HEADER_SIZE,
+            next_file_id: Arc::new(AtomicUsize::new(next_file_id + 1)),
+            merging: false,
+            last_timestamp,
+            recovery_report,
+            sync_mode,
+            unsynced_bytes: AtomicU64::new(0),
+            retired: Arc::default(),
+            max_file_size: opts.get_max_file_size(),
+            max_file_age: opts.get_max_file_age(),
+            merge_dead_ratio: opts.get_merge_dead_ratio(),
+            merge_dead_bytes: opts.get_merge_dead_bytes(),
+            cur_file_first_write: None,
+            corruption_policy: policy,
+            pending_batch,
+            read_only,
         })
     }
 
+    /// Finishes or discards an interrupted merge like a writer opening the
+    /// data directory would, then rewrites the damaged data files with what
+    /// can still be read from them. The directory must not be in use.
+    pub(super) fn repair(data_dir: &Path) -> Result<RepairReport, DBError> {
+        if let Some(manifest) = MergeManifest::load(data_dir)? {
+            manifest.apply(data_dir)?;
+        }
+        MergeManifest::discard_unfinished(data_dir)?;
+        repair::repair(data_dir, Self::data_files(data_dir, None)?)
+    }
+
+    /// Catches up with the writer of the data directory. Replays what was
+    /// appended to the newest file since the last time and the files created
+    /// since, or everything from scratch if a merge replaced files. Only for
+    /// readers.
+    pub(super) fn refresh(&mut self, keydir: &mut KeyDir) -> Result<(), DBError> {
+        let manifest = MergeManifest::load(&self.data_dir)?;
+        let mut files = Self::data_files(&self.data_dir, manifest.as_ref())?;
+        if self
+            .files
+            .keys()
+            .any(|file_id| !files.contains_key(file_id))
+        {
+            let mut new_keydir = KeyDir::new();
+            let mut replayer = Replayer::new(&mut new_keydir);
+            self.files = Self::to_log_files(
+                files,
+                &mut replayer,
+                self.corruption_policy,
+                true,
+                &mut self.recovery_report,
+            )?;
+            self.pending_batch = replayer.into_batch();
+            *keydir = new_keydir;
+            return Ok(());
+        }
+
+        let new_files = match self.files.last_key_value() {
+            Some((file_id, _)) => files.split_off(&(file_id + 1)),
+            None => files,
+        };
+        let mut replayer = Replayer::resume(keydir, self.pending_batch.take());
+        if let Some(log_file) = self.files.values_mut().next_back() {
+            log_file.follow(
+                &mut replayer,
+                new_files.is_empty(),
+                self.corruption_policy,
+                &mut self.recovery_report,
+            )?;
+        }
+        let new_files = Self::to_log_files(
+            new_files,
+            &mut replayer,
+            self.corruption_policy,
+            true,
+            &mut self.recovery_report,
+        )?;
+        self.files.extend(new_files);
+        self.pending_batch = replayer.into_batch();
+
+        Ok(())
+    }
+
     pub(super) fn get(&self, keydir_entry: &KeyDirEntry) -> Result<Value, DBError> {
-        let KeyDirEntry {
-            file_id,
-            value_sz,
-            value_pos,
-        } = keydir_entry;
-        let log_file = self.get_file(*file_id);
-        let mut buf_reader = BufReader::with_capacity(*value_sz as usize, log_file.get_file());
-        buf_reader.seek(SeekFrom::Start(*value_pos))?;
-        let mut buf = vec![0; *value_sz as usize];
-        buf_reader.read_exact(&mut buf)?;
+        let log_file = self.get_file(keydir_entry.file_id);
+        file_set::read_value(log_file.get_file(), keydir_entry)
+    }
 
-        Ok(buf)
+    /// Returns read handles on the current data files.
+    pub(super) fn file_set(&self) -> FileSet {
+        let files = self
+            .files
+            .iter()
+            .map(|(file_id, log_file)| (*file_id, log_file.get_file().clone()))
+            .collect();
+        FileSet::new(files, self.retired.clone())
     }
 
+    /// Appends a value, which expires `ttl` after now if there is a `ttl`.
     pub(super) fn put(
         &mut self,
         key: &Key,
         value: &Value,
-        sync_on_put: bool,
+        ttl: Option<Duration>,
     ) -> Result<KeyDirEntry, DBError> {
-        self.append(
-            LogEntry::new_live_entry(key.clone(), value.clone()),
-            sync_on_put,
-        )
+        let timestamp = self.next_timestamp();
+        let mut entry = LogEntry::new_live_entry(key.clone(), value.clone(), timestamp);
+        if let Some(ttl) = ttl {
+            entry = entry.with_expiry(timestamp.saturating_add(ttl.as_micros() as u64));
+        }
+        Ok(self.append(vec![entry])?.pop().unwrap())
     }
 
-    pub(super) fn delete(&mut self, key: &Key, sync_on_put: bool) -> Result<KeyDirEntry, DBError> {
-        self.append(LogEntry::new_tombstone_entry(key.clone()), sync_on_put)
+    pub(super) fn delete(&mut self, key: &Key) -> Result<KeyDirEntry, DBError> {
+        let timestamp = self.next_timestamp();
+        let entry = LogEntry::new_tombstone_entry(key.clone(), timestamp);
+        Ok(self.append(vec![entry])?.pop().unwrap())
     }
 
-    pub(super) fn put_on_merge(
+    /// Appends the operations of a batch, flagged so that replaying the log
+    /// applies all of them or none. Returns one entry per operation. A batch
+    /// too large to hold in memory is appended in parts, `first` and `last`
+    /// telling whether `ops` start and end it, with nothing else appended in
+    /// between.
+    pub(super) fn write_batch(
         &mut self,
-        key: &Key,
-        value: Value,
-        sync_on_put: bool,
-    ) -> Result<KeyDirEntry, DBError> {
-        let entry = LogEntry::new_live_entry(key.clone(), value);
-        let entry_sz = entry.total_size();
-        if self.cur_merged_file_sz + entry_sz > LogFile::MAX_FILE_SIZE
-            || self.cur_merged_file_sz == 0
-        {
-            self.create_new_merge_file()?;
+        ops: &[BatchOp],
+        first: bool,
+        last: bool,
+    ) -> Result<Vec<KeyDirEntry>, DBError> {
+        let last_op = ops.len() - 1;
+        let entries = ops
+            .iter()
+            .enumerate()
+            .map(|(i, op)| {
+                let timestamp = self.next_timestamp();
+                let entry = match op {
+                    BatchOp::Put(key, value, expiry) => {
+                        let entry = LogEntry::new_live_entry(key.clone(), value.clone(), timestamp);
+                        match expiry {
+                            Some(expiry) => entry.with_expiry(*expiry),
+                            None => entry,
+                        }
+                    }
+                    BatchOp::Delete(key) => LogEntry::new_tombstone_entry(key.clone(), timestamp),
+                };
+                entry.in_batch(first && i == 0, last && i == last_op)
+            })
+            .collect();
+        self.append(entries)
+    }
+
+    /// Returns the files to merge: those in which the dead bytes reach one of
+    /// the thresholds, given what `keydir_stats` says is live, or every file
+    /// if there is no threshold.
+    pub(super) fn files_to_merge(&self, keydir_stats: &KeyDirStats) -> BTreeSet<FileId> {
+        if self.merge_dead_ratio.is_none() && self.merge_dead_bytes.is_none() {
+            return self.files.keys().copied().collect();
         }
-        self.cur_merged_file_sz += entry_sz;
-        let log_file = self.get_current_merged_file();
-        let value_pos = log_file.append_entry(entry.clone(), sync_on_put)?;
+        self.file_stats(keydir_stats)
+            .into_iter()
+            .filter(|stats| {
+                let entries_sz = stats.live_bytes + stats.dead_bytes;
+                let ratio_reached = self.merge_dead_ratio.is_some_and(|ratio| {
+                    entries_sz > 0 && stats.dead_bytes as f64 >= ratio * entries_sz as f64
+                });
+                let bytes_reached = self
+                    .merge_dead_bytes
+                    .is_some_and(|dead_bytes| stats.dead_bytes >= dead_bytes);
+                ratio_reached || bytes_reached
+            })
+            .map(|stats| stats.file_id)
+            .collect()
+    }
 
-        Ok(KeyDirEntry::new(
-            log_file.get_file_id(),
-            entry.value_size(),
-            value_pos,
+    /// Starts merging the data files `file_ids`, given the keydir as it is
+    /// now. The active file is sealed first if it is one of them, so that
+    /// writing can go on in a new one while the merge runs.
+    pub(super) fn start_merge(
+        &mut self,
+        file_ids: BTreeSet<FileId>,
+        keydir: KeyDir,
+    ) -> Result<Merger, DBError> {
+        if self.merging {
+            return Err(DBError::LockError(
+                "another merge is in progress".to_string(),
+            ));
+        }
+        if let Some(file_id) = file_ids.iter().find(|id| !self.files.contains_key(id)) {
+            return Err(
+                io::Error::new(ErrorKind::NotFound, format!("no data file {}", file_id)).into(),
+            );
+        }
+        if file_ids.contains(&self.get_current_file().get_file_id()) {
+            self.create_new_file()?;
+        }
+        let oldest_kept = self
+            .files
+            .keys()
+            .copied()
+            .find(|file_id| !file_ids.contains(file_id));
+        let sources = file_ids
+            .iter()
+            .map(|file_id| (*file_id, self.get_file(*file_id).share()))
+            .collect();
+        self.merging = true;
+        Ok(Merger::new(
+            self.data_dir.clone(),
+            sources,
+            oldest_kept,
+            keydir,
+            self.next_file_id.clone(),
+            self.max_file_size,
         ))
     }
 
-    pub(super) fn finish_merge(&mut self) -> Result<(), DBError> {
-        self.cur_file_sz = self.cur_merged_file_sz;
-        self.cur_merged_file_sz = 0;
-        self.files = self.merged_files.take();
-        self.merged_files = Cell::new(vec![]);
+    /// Makes the merge output durable and writes the manifest that commits
+    /// it. From then on the merge survives a crash, and
+    /// [`Log::install_merge`] must follow.
+    pub(super) fn commit_merge(&mut self, merger: &mut Merger) -> Result<(), DBError> {
+        // Writing goes on in a new file after the merge output, so an empty
+        // active file can go with the replaced files.
+        if self.merge_output_is_newer(merger) && self.cur_file_sz == LogFile::HEADER_SIZE {
+            merger.replace(self.get_current_file().get_file_id());
+        }
+        merger.seal()?.write(&self.data_dir)
+    }
 
-        for log_file in &mut self.files {
-            log_file.change_extension()?;
+    /// Switches from the replaced files to the merge output and deletes them,
+    /// except for those still used by a snapshot, which are moved aside until
+    /// it is gone. If this is interrupted, [`Log::from_disk`] finishes the job.
+    pub(super) fn install_merge(&mut self, mut merger: Merger) -> Result<(), DBError> {
+        let manifest = merger.seal()?;
+        // Whatever is written from now on must replay after the merge output.
+        if self.merge_output_is_newer(&merger) {
+            self.create_new_file()?;
+        }
+        let (replaced, merged_files) = merger.into_files();
+        for file_id in replaced {
+            let log_file = self.files.remove(&file_id).unwrap();
+            log_file.retire_if_shared(&self.retired)?;
         }
+        for mut log_file in merged_files {
+            log_file.set_extension(LogFile::EXTENSION);
+            self.files.insert(log_file.get_file_id(), log_file);
+        }
+        self.merging = false;
+
+        manifest.apply(&self.data_dir)?;
+        // A snapshot may have been dropped since its files were retired.
+        self.retired.release();
         Ok(())
     }
 
-    pub(super) fn sync(&mut self) -> Result<(), DBError> {
-        for file in &mut self.files {
-            file.get_file_mut().flush()?;
+    /// Throws the merge output away after a failed or stopped merge.
+    pub(super) fn abort_merge(&mut self, merger: Merger) -> Result<(), DBError> {
+        self.merging = false;
+        MergeManifest::remove(&self.data_dir)?;
+        merger.discard()
+    }
+
+    /// Seals the active file of a writer and returns the files to put in a
+    /// checkpoint. Every file but the newest is immutable from then on, and
+    /// only the valid prefix of the newest one is taken, since a writer may
+    /// be appending to it.
+    pub(super) fn checkpoint(&mut self) -> Result<Checkpoint, DBError> {
+        if !self.read_only && self.cur_file_sz > LogFile::HEADER_SIZE {
+            self.create_new_file()?;
         }
-        Ok(())
+        let newest_file_id = self.files.keys().next_back().copied();
+        let files = self
+            .files
+            .iter()
+            .map(|(file_id, log_file)| {
+                let valid_sz =
+                    (Some(*file_id) == newest_file_id).then(|| log_file.get_valid_size());
+                let checkpoint_file = CheckpointFile::new(log_file.get_path().clone(), valid_sz);
+                (*file_id, checkpoint_file)
+            })
+            .collect();
+        Ok(Checkpoint::new(self.file_set(), files))
     }
 
-    fn to_log_files(files: Vec<PathBuf>, keydir: &mut KeyDir) -> Result<Vec<LogFile>, DBError> {
-        let mut files = files
+    /// Returns the statistics of every data file, given what `keydir_stats`
+    /// says is live in them.
+    pub(super) fn file_stats(&self, keydir_stats: &KeyDirStats) -> Vec<FileStats> {
+        self.files
+            .values()
+            .map(|log_file| {
+                let file_id = log_file.get_file_id();
+                let size = log_file.get_valid_size();
+                let version = log_file.get_version();
+                let live_bytes = keydir_stats.files.get(&file_id).map_or(0, |live| {
+                    let header_sz = LogEntry::header_size_of(version, false);
+                    let expiry_sz = LogEntry::header_size_of(version, true) - header_sz;
+                    live.bytes + live.entries * header_sz + live.expiring * expiry_sz
+                });
+                FileStats {
+                    file_id,
+                    size,
+                    live_bytes,
+                    dead_bytes: size.saturating_sub(log_file.data_offset() + live_bytes),
+                    tombstones: log_file.get_tombstones(),
+                }
+            })
+            .collect()
+    }
+
+    /// Returns the files to check along with `keydir`. The newest file is
+    /// only checked up to where it was last appended to or read, since a
+    /// writer may be appending to it.
+    pub(super) fn verifier(&self, keydir: KeyDir) -> Verifier {
+        let newest_file_id = self.files.keys().next_back().copied();
+        let files = self
+            .files
+            .iter()
+            .map(|(file_id, log_file)| {
+                let valid_sz =
+                    (Some(*file_id) == newest_file_id).then(|| log_file.get_valid_size());
+                let verifier_file =
+                    VerifierFile::new(log_file.get_version(), log_file.data_offset(), valid_sz);
+                (*file_id, verifier_file)
+            })
+            .collect();
+        Verifier::new(self.file_set(), files, keydir)
+    }
+
+    #[inline]
+    pub(super) fn get_recovery_report(&self) -> &RecoveryReport {
+        &self.recovery_report
+    }
+
+    /// Syncs the active file. Sealed files were synced when they were sealed,
+    /// unless the sync mode is [`SyncMode::Never`].
+    pub(super) fn sync(&self) -> Result<(), DBError> {
+        let unsynced_bytes = self.unsynced_bytes.swap(0, Ordering::Relaxed);
+        self.files
+            .values()
+            .next_back()
+            .unwrap()
+            .sync()
+            .inspect_err(|_| {
+                self.unsynced_bytes
+                    .fetch_add(unsynced_bytes, Ordering::Relaxed);
+            })
+    }
+
+    #[inline]
+    pub(super) fn get_unsynced_bytes(&self) -> u64 {
+        self.unsynced_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Lists the data files by id. With the `manifest` of a committed merge
+    /// that has not been applied yet, they are listed as they will be once it
+    /// is.
+    fn data_files(
+        data_dir: &Path,
+        manifest: Option<&MergeManifest>,
+    ) -> Result<BTreeMap<FileId, PathBuf>, DBError> {
+        let mut files = fs::read_dir(data_dir)?
+            .filter_map(|path| {
+                path.ok().map(|path| path.path()).filter(|path| {
+                    path.is_file() && path.extension() == Some(OsStr::new(LogFile::EXTENSION))
+                })
+            })
+            .collect();
+        if let Some(manifest) = manifest {
+            files = manifest.resolve(data_dir, files);
+        }
+
+        Ok(files
             .into_iter()
             .filter_map(|path| {
                 path.file_stem()
@@ -144,60 +510,147 @@ impl Log {
                     .and_then(|file_stem| file_stem.parse::<FileId>().ok())
                     .map(|file_id| (file_id, path))
             })
-            .map(|(file_id, path)| LogFile::open(file_id, path, keydir).map(|file| (file_id, file)))
-            .collect::<Result<Vec<(FileId, LogFile)>, DBError>>()?;
+            .collect())
+    }
 
-        files.sort_by_key(|(file_id, _)| *file_id);
-        Ok(files.into_iter().map(|(_, file)| file).collect())
+    fn to_log_files(
+        files: BTreeMap<FileId, PathBuf>,
+        replayer: &mut Replayer,
+        policy: CorruptionPolicy,
+        read_only: bool,
+        report: &mut RecoveryReport,
+    ) -> Result<BTreeMap<FileId, LogFile>, DBError> {
+        // Later files override earlier ones, so they must be replayed in order.
+        let newest_file_id = files.last_key_value().map(|(file_id, _)| *file_id);
+        files
+            .into_iter()
+            .map(|(file_id, path)| {
+                let newest = Some(file_id) == newest_file_id;
+                LogFile::open(file_id, path, replayer, newest, policy, read_only, report)
+                    .map(|f| (file_id, f))
+            })
+            .collect()
     }
 
     fn get_file(&self, file_id: FileId) -> &LogFile {
-        self.files.get(file_id as usize).unwrap()
+        self.files.get(&file_id).unwrap()
     }
 
     fn get_current_file(&mut self) -> &mut LogFile {
-        self.files.last_mut().unwrap()
+        self.files.values_mut().next_back().unwrap()
     }
 
-    fn get_current_merged_file(&mut self) -> &mut LogFile {
-        self.merged_files.get_mut().last_mut().unwrap()
+    /// Whether the merge output has files that replay after the active file.
+    fn merge_output_is_newer(&self, merger: &Merger) -> bool {
+        let cur_file_id = *self.files.keys().next_back().unwrap();
+        merger
+            .last_merged_file_id()
+            .is_some_and(|file_id| file_id > cur_file_id)
     }
 
-    fn append(&mut self, entry: LogEntry, sync_on_put: bool) -> Result<KeyDirEntry, DBError> {
-        let entry_sz = entry.total_size();
-        if self.cur_file_sz + entry_sz > LogFile::MAX_FILE_SIZE {
-            self.create_new_file()?;
+    fn next_timestamp(&mut self) -> u64 {
+        self.last_timestamp = now_micros().max(self.last_timestamp + 1);
+        self.last_timestamp
+    }
+
+    /// Appends `entries` with one write per data file they end up in, which
+    /// is more than one only if the active file is sealed on the way, and
+    /// syncs once at the end if the sync mode asks for it.
+    fn append(&mut self, entries: Vec<LogEntry>) -> Result<Vec<KeyDirEntry>, DBError> {
+        let mut keydir_entries = Vec::with_capacity(entries.len());
+        let mut chunk = vec![];
+        for entry in entries {
+            let entry_sz = entry.total_size();
+            if self.should_rotate(entry_sz) {
+                // Sealing the file syncs it unless the sync mode is `Never`.
+                self.append_chunk(&std::mem::take(&mut chunk), false, &mut keydir_entries)?;
+                self.create_new_file()?;
+            }
+            self.cur_file_sz += entry_sz;
+            chunk.push(entry);
         }
-        self.cur_file_sz += entry_sz;
-        let log_file = self.get_current_file();
-        let value_pos = log_file.append_entry(entry.clone(), sync_on_put)?;
+        let sync = self.sync_mode == SyncMode::Always;
+        self.append_chunk(&chunk, sync, &mut keydir_entries)?;
 
-        Ok(KeyDirEntry::new(
-            log_file.get_file_id(),
-            entry.value_size(),
-            value_pos,
-        ))
+        Ok(keydir_entries)
     }
 
-    fn create_new_file(&mut self) -> Result<(), DBError> {
-        let next_file_id = self.files.last().unwrap().get_file_id() + 1;
-        let log_file = LogFile::new(&self.data_dir, next_file_id, LogFile::EXTENSION)?;
-        self.files.push(log_file);
-        self.cur_file_sz = 0;
+    /// Writes `chunk` to the active file and adds its entries to
+    /// `keydir_entries`.
+    fn append_chunk(
+        &mut self,
+        chunk: &[LogEntry],
+        sync: bool,
+        keydir_entries: &mut Vec<KeyDirEntry>,
+    ) -> Result<(), DBError> {
+        if chunk.is_empty() {
+            return Ok(());
+        }
+        self.cur_file_first_write.get_or_insert_with(Instant::now);
+        let log_file = self.get_current_file();
+        let file_id = log_file.get_file_id();
+        let value_positions = log_file.append_entries(chunk, sync)?;
+        if !sync {
+            let chunk_sz = chunk.iter().map(|e| e.total_size()).sum();
+            self.unsynced_bytes.fetch_add(chunk_sz, Ordering::Relaxed);
+        }
+        keydir_entries.extend(chunk.iter().zip(value_positions).map(|(entry, value_pos)| {
+            KeyDirEntry::new(
+                file_id,
+                entry.value_size(),
+                value_pos,
+                entry.get_timestamp(),
+                entry.get_expiry(),
+            )
+        }));
 
         Ok(())
     }
 
-    fn create_new_merge_file(&mut self) -> Result<(), DBError> {
-        let next_file_id = if let Some(file) = self.merged_files.get_mut().last() {
-            file.get_file_id() + 1
-        } else {
-            0
+    /// Whether the active file must be sealed before appending `entry_sz`
+    /// more bytes, because it would grow too large or has been written to
+    /// for too long. An empty file is never sealed.
+    fn should_rotate(&self, entry_sz: SizeType) -> bool {
+        let too_old = match (self.max_file_age, self.cur_file_first_write) {
+            (Some(max_file_age), Some(first_write)) => first_write.elapsed() >= max_file_age,
+            _ => false,
         };
-        let log_file = LogFile::new(&self.data_dir, next_file_id, LogFile::MERGE_EXTENSION)?;
-        self.merged_files.get_mut().push(log_file);
-        self.cur_merged_file_sz = 0;
+        self.cur_file_sz > LogFile::HEADER_SIZE
+            && (self.cur_file_sz + entry_sz > self.max_file_size || too_old)
+    }
+
+    fn create_new_file(&mut self) -> Result<(), DBError> {
+        if self.sync_mode != SyncMode::Never {
+            self.sync()?;
+        }
+        self.get_current_file().write_hint()?;
+        let next_file_id = self.next_file_id.fetch_add(1, Ordering::Relaxed);
+        let log_file = Self::new_active_file(&self.data_dir, next_file_id, self.sync_mode)?;
+        self.files.insert(next_file_id, log_file);
+        self.cur_file_sz = LogFile::HEADER_SIZE;
+        self.cur_file_first_write = None;
 
         Ok(())
     }
+
+    /// Creates an empty data file to append to, making its directory entry
+    /// durable unless the sync mode is [`SyncMode::Never`].
+    fn new_active_file(
+        data_dir: &Path,
+        file_id: FileId,
+        sync_mode: SyncMode,
+    ) -> Result<LogFile, DBError> {
+        let log_file = LogFile::new(data_dir, file_id, LogFile::EXTENSION)?;
+        if sync_mode != SyncMode::Never {
+            log_file.sync()?;
+            sync_dir(data_dir)?;
+        }
+        Ok(log_file)
+    }
+}
+
+/// Makes renames and newly created files in `dir` durable.
+pub(super) fn sync_dir(dir: &Path) -> Result<(), DBError> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
 }
@@ -0,0 +1,164 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    bitcask::{
+        now_micros,
+        recovery::{QuarantinedSpan, RepairReport},
+        FileId, Key, SizeType,
+    },
+    error::DBError,
+};
+
+use super::{
+    hint_file::HintFile,
+    log_entry::LogEntry,
+    log_file::LogFile,
+    scanner::{self, Scanned, Scanner, Span},
+    sync_dir,
+};
+
+/// Position of an entry in the log, in replay order.
+type Position = (FileId, SizeType);
+
+const TMP_EXTENSION: &str = "repair";
+
+/// Reads the data `files` with a buffered reader, resyncing on the next
+/// valid entry after every damaged span, and rewrites the damaged files with
+/// the valid entries only. The spans go to the quarantine directory, along
+/// with a report of what was done.
+pub(super) fn repair(
+    data_dir: &Path,
+    files: BTreeMap<FileId, PathBuf>,
+) -> Result<RepairReport, DBError> {
+    let mut report = RepairReport {
+        files: files.len(),
+        ..Default::default()
+    };
+    let quarantine_dir = data_dir.join(LogFile::QUARANTINE_DIR);
+    let mut latest: BTreeMap<Key, Position> = BTreeMap::new();
+    let mut damaged: Vec<(Key, Position)> = vec![];
+    // Every damaged span, with the longest key an entry in it could have had.
+    let mut spans_room: Vec<(Position, SizeType)> = vec![];
+    for (file_id, path) in files {
+        let file = File::open(&path)?;
+        let version = LogFile::read_version(&file, &path)?;
+        let data_offset = if version == LogEntry::LEGACY_VERSION {
+            0
+        } else {
+            LogFile::HEADER_SIZE
+        };
+        let file_sz = file.metadata()?.len();
+        let mut spans: Vec<Span> = vec![];
+        for scanned in Scanner::new(&file, version, data_offset.min(file_sz), file_sz, true) {
+            match scanned? {
+                Scanned::Entry { offset, entry } => {
+                    latest.insert(entry.get_key(), (file_id, offset));
+                    report.entries += 1;
+                }
+                Scanned::Damaged { offset, len, key } => {
+                    // Only where a span starts is there likely to be a real
+                    // entry, rather than garbage that happens to frame.
+                    if let Some(key) = key {
+                        damaged.push((key, (file_id, offset)));
+                    }
+                    let header_sz = LogEntry::header_size_of(version, false);
+                    spans_room.push(((file_id, offset), len.saturating_sub(header_sz)));
+                    spans.push((offset, len));
+                }
+            }
+        }
+        if spans.is_empty() {
+            continue;
+        }
+
+        fs::create_dir_all(&quarantine_dir)?;
+        for &(offset, len) in &spans {
+            let quarantine_path = quarantine_dir.join(format!("{}-{}.bad", file_id, offset));
+            scanner::copy_span(&file, (offset, len), &quarantine_path)?;
+            report.quarantined.push(QuarantinedSpan {
+                file_id,
+                offset,
+                len,
+                path: quarantine_path,
+            });
+        }
+        sync_dir(&quarantine_dir)?;
+        let tmp_path = path.with_extension(TMP_EXTENSION);
+        scanner::copy_without(&file, &spans, &tmp_path)?;
+        fs::rename(&tmp_path, &path)?;
+        // The hint describes the file as it was.
+        let hint_path = path.with_extension(HintFile::EXTENSION);
+        if hint_path.exists() {
+            fs::remove_file(hint_path)?;
+        }
+        sync_dir(data_dir)?;
+    }
+
+    if report.quarantined.is_empty() {
+        return Ok(report);
+    }
+    // The longest key an entry in a span at or after each one could have had.
+    let mut room_after = spans_room;
+    for i in (0..room_after.len().saturating_sub(1)).rev() {
+        room_after[i].1 = room_after[i].1.max(room_after[i + 1].1);
+    }
+    let mut keys_at_risk: Vec<Key> = latest
+        .iter()
+        .filter(|(key, position)| {
+            let first_after = room_after.partition_point(|(span, _)| span < position);
+            room_after
+                .get(first_after)
+                .is_some_and(|(_, room)| key.len() as SizeType <= *room)
+        })
+        .map(|(key, _)| key.clone())
+        .collect();
+    for (key, position) in damaged {
+        if latest.get(&key).is_none_or(|latest| *latest < position) {
+            keys_at_risk.push(key);
+        }
+    }
+    keys_at_risk.sort_unstable();
+    keys_at_risk.dedup();
+    report.keys_at_risk = keys_at_risk;
+
+    let report_path = quarantine_dir.join(format!("repair-{}.txt", now_micros()));
+    write_synced(&report_path, write_report(&report).as_bytes())?;
+    sync_dir(&quarantine_dir)?;
+    report.report_path = Some(report_path);
+
+    Ok(report)
+}
+
+/// One line per quarantined span, followed by one line per key at risk,
+/// with keys in hex.
+fn write_report(report: &RepairReport) -> String {
+    let mut text = String::new();
+    for span in &report.quarantined {
+        let _ = writeln!(
+            text,
+            "quarantined file {} offset {} length {}: {}",
+            span.file_id,
+            span.offset,
+            span.len,
+            span.path.display()
+        );
+    }
+    for key in &report.keys_at_risk {
+        let key: String = key.iter().map(|b| format!("{:02x}", b)).collect();
+        let _ = writeln!(text, "key at risk: {}", key);
+    }
+    text
+}
+
+fn write_synced(path: &Path, contents: &[u8]) -> Result<(), DBError> {
+    let mut file = File::create(path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    Ok(())
+}
@@ -0,0 +1,166 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, ErrorKind, Read},
+    os::unix::fs::FileExt,
+    path::Path,
+};
+
+use crate::{
+    bitcask::{Key, SizeType},
+    error::DBError,
+};
+
+use super::{file_set::PositionedReader, log_entry::LogEntry};
+
+/// Offset and length of a damaged span of a data file.
+pub(super) type Span = (SizeType, SizeType);
+
+/// What [`Scanner`] finds next in a data file.
+pub(super) enum Scanned {
+    /// A valid entry starting at `offset`.
+    Entry { offset: SizeType, entry: LogEntry },
+    /// `len` bytes from `offset` that hold no valid entry, with the key of
+    /// the entry that seemed to start there if its header could be read.
+    Damaged {
+        offset: SizeType,
+        len: SizeType,
+        key: Option<Key>,
+    },
+}
+
+/// Reads the entries of a data file up to `end` with a buffered reader.
+/// After a damaged span it resyncs on the next valid entry, looking for it
+/// one byte at a time through a window of [`Scanner::WINDOW`] bytes, or it
+/// gives up on the rest of the file if it was not asked to resync. Each
+/// offset is dropped on the checksum and sizes of the header found there
+/// before any key or value is read, so resyncing takes time linear in the
+/// damaged span, except in the legacy format, whose headers have no
+/// checksum.
+pub(super) struct Scanner<'a> {
+    file: &'a File,
+    version: u32,
+    end: SizeType,
+    resync: bool,
+    cursor: SizeType,
+    reader: BufReader<PositionedReader<'a>>,
+    window: Vec<u8>,
+}
+
+impl<'a> Scanner<'a> {
+    const WINDOW: SizeType = 64 << 10;
+
+    pub(super) fn new(
+        file: &'a File,
+        version: u32,
+        start: SizeType,
+        end: SizeType,
+        resync: bool,
+    ) -> Self {
+        Self {
+            file,
+            version,
+            end,
+            resync,
+            cursor: start,
+            reader: BufReader::new(PositionedReader::new(file, start)),
+            window: vec![],
+        }
+    }
+
+    /// Returns the offset of the first valid entry after `from`, or `end` if
+    /// there is none.
+    fn resync(&mut self, from: SizeType) -> Result<SizeType, DBError> {
+        let mut start = from;
+        while start < self.end {
+            let len = (self.end - start).min(Self::WINDOW);
+            self.window.resize(len as usize, 0);
+            self.file.read_exact_at(&mut self.window, start)?;
+            for i in 0..len {
+                let offset = start + i;
+                // Only an entry reaching past the window is read from the
+                // file.
+                let rest =
+                    PositionedReader::new(self.file, start + len).take(self.end - start - len);
+                let mut reader = (&self.window[i as usize..]).chain(rest);
+                match LogEntry::deserialize_version(&mut reader, self.version, self.end - offset) {
+                    Ok(_) => return Ok(offset),
+                    Err(DBError::IOError(e)) if e.kind() != ErrorKind::UnexpectedEof => {
+                        return Err(e.into())
+                    }
+                    Err(_) => {}
+                }
+            }
+            start += len;
+        }
+        Ok(self.end)
+    }
+}
+
+impl Iterator for Scanner<'_> {
+    type Item = Result<Scanned, DBError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.cursor >= self.end {
+            return None;
+        }
+        let offset = self.cursor;
+        let key = match LogEntry::deserialize_unchecked(
+            &mut self.reader,
+            self.version,
+            self.end - offset,
+        ) {
+            Ok(entry) if entry.is_valid() => {
+                self.cursor += entry.total_size();
+                return Some(Ok(Scanned::Entry { offset, entry }));
+            }
+            Ok(entry) => Some(entry.get_key()),
+            Err(DBError::IOError(e)) if e.kind() != ErrorKind::UnexpectedEof => {
+                return Some(Err(e.into()))
+            }
+            Err(_) => None,
+        };
+        let next = if self.resync {
+            match self.resync(offset + 1) {
+                Ok(next) => next,
+                Err(e) => return Some(Err(e)),
+            }
+        } else {
+            self.end
+        };
+        self.cursor = next;
+        self.reader = BufReader::new(PositionedReader::new(self.file, next));
+        Some(Ok(Scanned::Damaged {
+            offset,
+            len: next - offset,
+            key,
+        }))
+    }
+}
+
+/// Copies the bytes of `span` in `file` into a new file at `path`.
+pub(super) fn copy_span(file: &File, (offset, len): Span, path: &Path) -> Result<(), DBError> {
+    let mut dest_file = File::create(path)?;
+    io::copy(
+        &mut PositionedReader::new(file, offset).take(len),
+        &mut dest_file,
+    )?;
+    dest_file.sync_all()?;
+    Ok(())
+}
+
+/// Copies `file` without the `spans`, which are in order, into a new file
+/// at `path`.
+pub(super) fn copy_without(file: &File, spans: &[Span], path: &Path) -> Result<(), DBError> {
+    let file_sz = file.metadata()?.len();
+    let mut dest_file = File::create(path)?;
+    let mut pos = 0;
+    for &(offset, len) in spans.iter().chain([(file_sz, 0)].iter()) {
+        io::copy(
+            &mut PositionedReader::new(file, pos).take(offset - pos),
+            &mut dest_file,
+        )?;
+        pos = offset + len;
+    }
+    dest_file.sync_all()?;
+    Ok(())
+}
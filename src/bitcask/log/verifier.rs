@@ -0,0 +1,158 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{BufReader, ErrorKind},
+};
+
+use crate::{
+    bitcask::{
+        keydir::KeyDir,
+        verify::{BadEntry, BadKey, VerifyReport},
+        FileId, SizeType,
+    },
+    error::DBError,
+};
+
+use super::{file_set::PositionedReader, log_entry::LogEntry, FileSet};
+
+/// The data files of the log and the keydir at one point in time, checked
+/// by [`Verifier::verify`]. Like a checkpoint, the files are held open, so
+/// writes and merges can go on meanwhile.
+pub(crate) struct Verifier {
+    file_set: FileSet,
+    files: BTreeMap<FileId, VerifierFile>,
+    keydir: KeyDir,
+}
+
+pub(super) struct VerifierFile {
+    /// Format version of the entries in the file.
+    version: u32,
+    /// Offset of the first entry.
+    data_offset: SizeType,
+    /// Number of bytes to check if the file may still grow. Immutable files
+    /// are checked to the end.
+    valid_sz: Option<SizeType>,
+}
+
+impl VerifierFile {
+    pub(super) fn new(version: u32, data_offset: SizeType, valid_sz: Option<SizeType>) -> Self {
+        Self {
+            version,
+            data_offset,
+            valid_sz,
+        }
+    }
+}
+
+impl Verifier {
+    pub(super) fn new(
+        file_set: FileSet,
+        files: BTreeMap<FileId, VerifierFile>,
+        keydir: KeyDir,
+    ) -> Self {
+        Self {
+            file_set,
+            files,
+            keydir,
+        }
+    }
+
+    /// Reads every entry of every file, checking its framing and checksum,
+    /// and ticks off the keydir entries pointing at valid values on the way.
+    pub(crate) fn verify(&self) -> Result<VerifyReport, DBError> {
+        let mut report = VerifyReport {
+            files: self.files.len(),
+            ..Default::default()
+        };
+        let mut expected: HashMap<_, _> = self
+            .keydir
+            .iter()
+            .map(|(key, entry)| ((entry.file_id, entry.value_pos), (key, entry)))
+            .collect();
+        for (file_id, verifier_file) in &self.files {
+            let file = self.file_set.get_file(*file_id);
+            let file_sz = match verifier_file.valid_sz {
+                Some(valid_sz) => valid_sz,
+                None => file.metadata()?.len(),
+            };
+            let mut cursor = verifier_file.data_offset;
+            let mut reader = BufReader::new(PositionedReader::new(file, cursor));
+            while cursor < file_sz {
+                let entry = match LogEntry::deserialize_unchecked(
+                    &mut reader,
+                    verifier_file.version,
+                    file_sz - cursor,
+                ) {
+                    Ok(entry) => entry,
+                    Err(DBError::IOError(e)) if e.kind() != ErrorKind::UnexpectedEof => {
+                        return Err(e.into())
+                    }
+                    Err(e) => {
+                        let reason = match e {
+                            DBError::DataError(reason) => reason,
+                            _ => "truncated entry".to_string(),
+                        };
+                        report.bad_entries.push(BadEntry {
+                            file_id: *file_id,
+                            offset: cursor,
+                            len: file_sz - cursor,
+                            key: None,
+                            reason,
+                        });
+                        break;
+                    }
+                };
+                let entry_sz = entry.total_size();
+                if !entry.is_valid() {
+                    report.bad_entries.push(BadEntry {
+                        file_id: *file_id,
+                        offset: cursor,
+                        len: entry_sz,
+                        key: Some(entry.get_key()),
+                        reason: "invalid checksum".to_string(),
+                    });
+                    cursor += entry_sz;
+                    continue;
+                }
+                report.entries += 1;
+                let value_pos = cursor + entry.get_value_offset();
+                if let Some((key, keydir_entry)) = expected.remove(&(*file_id, value_pos)) {
+                    let reason = if entry.is_tombstone() {
+                        Some("points at a tombstone")
+                    } else if entry.get_key_ref() != key {
+                        Some("points at the value of another key")
+                    } else if entry.value_size() != keydir_entry.value_sz {
+                        Some("has the wrong value size")
+                    } else {
+                        None
+                    };
+                    if let Some(reason) = reason {
+                        report.bad_keys.push(BadKey {
+                            key: key.clone(),
+                            file_id: *file_id,
+                            value_pos,
+                            reason: reason.to_string(),
+                        });
+                    }
+                }
+                cursor += entry_sz;
+            }
+        }
+
+        for ((file_id, value_pos), (key, _)) in expected {
+            let reason = if self.files.contains_key(&file_id) {
+                "points at no valid entry"
+            } else {
+                "points at a missing data file"
+            };
+            report.bad_keys.push(BadKey {
+                key: key.clone(),
+                file_id,
+                value_pos,
+                reason: reason.to_string(),
+            });
+        }
+        report.bad_keys.sort_by(|a, b| a.key.cmp(&b.key));
+
+        Ok(report)
+    }
+}
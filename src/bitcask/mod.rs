@@ -1,25 +1,60 @@
 //! A tiny but full-fledged database engine based on bitcask.
 
 use std::{
+    io::{Read, Write},
+    ops::RangeBounds,
     path::PathBuf,
     sync::{Arc, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use super::error::DBError;
-pub(crate) use opts::Opts;
+use auto_merger::{AutoMerger, Triggers};
+use batch::WriteBatch;
+use export::ExportFormat;
+use flusher::Flusher;
+use iter::{Iter, Keys, Values};
+pub(crate) use opts::{Opts, SyncMode};
+use recovery::{RecoveryReport, RepairReport};
+use refresher::Refresher;
+use snapshot::Snapshot;
+use stats::Stats;
 use storage::Storage;
+use transaction::Transaction;
+use verify::VerifyReport;
 
+mod auto_merger;
+pub mod backup;
+pub mod batch;
+pub mod export;
+mod flusher;
+pub mod iter;
 mod keydir;
+mod lock;
 mod log;
 pub mod opts;
+pub mod recovery;
+mod refresher;
+pub mod snapshot;
+pub mod stats;
 mod storage;
+pub mod transaction;
+pub mod verify;
 
 type FileId = usize;
 type SizeType = u64;
 type Key = Vec<u8>;
 type Value = Vec<u8>;
 
-/// Type that manages the database. It encapsulates [`Storage`] which is the 
+/// The current time in microseconds since the Unix epoch.
+fn now_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or(0)
+}
+
+/// Type that manages the database. It encapsulates [`Storage`] which is the
 /// underlying type of the database. This type is thread-safe by using a
 /// [`RwLock`].
 pub struct BitCask {
@@ -27,41 +62,106 @@ pub struct BitCask {
     storage: Arc<RwLock<Storage>>,
     /// whether mutable or not.
     mutable: bool,
-    /// whether to sync on put.
-    sync_on_put: bool,
+    /// when to sync written data.
+    sync_mode: SyncMode,
+    /// background syncing for [`SyncMode::Periodic`].
+    flusher: Option<Flusher>,
+    /// background refreshing for following readers.
+    refresher: Option<Refresher>,
+    /// background merging for writers.
+    auto_merger: Option<AutoMerger>,
+    /// whether [`BitCask::close`] has been called.
+    closed: bool,
 }
 
 impl BitCask {
     pub fn open_with_opts<T: Into<PathBuf>>(data_dir: T, opts: Opts) -> Result<Self, DBError> {
-        let s = Storage::new(data_dir)?;
+        let s = Storage::new(data_dir, &opts)?;
+        let storage = Arc::new(RwLock::new(s));
+        let sync_mode = opts.get_sync_mode();
+        let flusher = match sync_mode {
+            SyncMode::Periodic { interval, .. } if opts.is_mutable() => {
+                Some(Flusher::spawn(Arc::downgrade(&storage), interval))
+            }
+            _ => None,
+        };
+        let refresher = match opts.get_refresh_interval() {
+            Some(interval) if opts.is_following() => {
+                Some(Refresher::spawn(Arc::downgrade(&storage), interval))
+            }
+            _ => None,
+        };
+        let auto_merger = match opts.get_auto_merge_interval() {
+            Some(interval) if opts.is_mutable() => Some(AutoMerger::spawn(
+                Arc::downgrade(&storage),
+                interval,
+                Triggers::new(&opts),
+                opts.get_auto_merge_rate(),
+            )),
+            _ => None,
+        };
 
         Ok(Self {
-            storage: Arc::new(RwLock::new(s)),
+            storage,
             mutable: opts.is_mutable(),
-            sync_on_put: opts.do_sync_on_put(),
+            sync_mode,
+            flusher,
+            refresher,
+            auto_merger,
+            closed: false,
         })
     }
 
     pub fn open<T: Into<PathBuf>>(data_dir: T) -> Result<Self, DBError> {
-        let s = Storage::new(data_dir)?;
+        let s = Storage::new(data_dir, &Opts::new(false, SyncMode::Never))?;
 
         Ok(Self {
             storage: Arc::new(RwLock::new(s)),
             mutable: false,
-            sync_on_put: false,
+            sync_mode: SyncMode::Never,
+            flusher: None,
+            refresher: None,
+            auto_merger: None,
+            closed: false,
         })
     }
 
+    /// Salvages a data directory that cannot be opened because of damaged
+    /// data files. Every file is read through a buffer, and past a damaged
+    /// span byte by byte up to the next valid entry, and the damaged files
+    /// are rewritten with the valid entries only. The spans are moved into
+    /// the `quarantine` directory, along with a report listing them and the
+    /// keys that may have lost their latest version. The data directory must
+    /// not be in use.
+    pub fn repair<T: Into<PathBuf>>(data_dir: T) -> Result<RepairReport, DBError> {
+        Storage::repair(&data_dir.into())
+    }
+
     pub fn get(&self, key: &Key) -> Result<Option<Value>, DBError> {
         self.storage.read().unwrap().get(key)
     }
 
     pub fn put(&mut self, key: &Key, value: &Value) -> Result<(), DBError> {
+        self.check_open("write")?;
         if self.mutable {
-            self.storage
-                .write()
-                .unwrap()
-                .put(key, value, self.sync_on_put)
+            let mut storage = self.storage.write().unwrap();
+            storage.put(key, value, None)?;
+            self.after_write(&storage)
+        } else {
+            Err(DBError::OptionError(
+                "tried to write in read-only access".to_string(),
+            ))
+        }
+    }
+
+    /// Stores a value that expires once `ttl` has passed. From then on the
+    /// key is treated as deleted, and the next merge drops it for good.
+    pub fn put_with_ttl(&mut self, key: &Key, value: &Value, ttl: Duration) -> Result<(), DBError> {
+        self.check_open("write")?;
+        if self.mutable {
+            let mut storage = self.storage.write().unwrap();
+            storage.put(key, value, Some(ttl))?;
+            self.after_write(&storage)
         } else {
             Err(DBError::OptionError(
                 "tried to write in read-only access".to_string(),
@@ -70,8 +170,11 @@ impl BitCask {
     }
 
     pub fn delete(&mut self, key: &Key) -> Result<(), DBError> {
+        self.check_open("delete")?;
         if self.mutable {
-            self.storage.write().unwrap().delete(key, self.sync_on_put)
+            let mut storage = self.storage.write().unwrap();
+            storage.delete(key)?;
+            self.after_write(&storage)
         } else {
             Err(DBError::OptionError(
                 "tried to delete in read-only access".to_string(),
@@ -79,34 +182,297 @@ impl BitCask {
         }
     }
 
+    /// Applies every write in `batch` with a single append. After a crash
+    /// either all of them are found or none.
+    pub fn write_batch(&mut self, batch: &WriteBatch) -> Result<(), DBError> {
+        self.check_open("write")?;
+        if self.mutable {
+            let mut storage = self.storage.write().unwrap();
+            storage.write_batch(batch)?;
+            self.after_write(&storage)
+        } else {
+            Err(DBError::OptionError(
+                "tried to write in read-only access".to_string(),
+            ))
+        }
+    }
+
+    /// Starts a transaction reading from a snapshot of the database as it is
+    /// now. Nothing is written until it is passed to [`BitCask::commit`].
+    pub fn transaction(&self) -> Transaction {
+        Transaction::new(self.snapshot())
+    }
+
+    /// Applies the writes of `transaction` atomically, or fails with
+    /// [`DBError::ConflictError`] if a key it read has been written since it
+    /// started.
+    pub fn commit(&mut self, transaction: Transaction) -> Result<(), DBError> {
+        self.check_open("commit")?;
+        if self.mutable {
+            let (reads, deletions_epoch, batch) = transaction.into_commit();
+            let mut storage = self.storage.write().unwrap();
+            storage.commit(&reads, deletions_epoch, &batch)?;
+            self.after_write(&storage)
+        } else {
+            Err(DBError::OptionError(
+                "tried to commit in read-only access".to_string(),
+            ))
+        }
+    }
+
+    /// Returns what was repaired in the data files when the database was
+    /// opened.
+    pub fn recovery_report(&self) -> RecoveryReport {
+        self.storage.read().unwrap().get_recovery_report().clone()
+    }
+
+    /// Returns how many keys there are, how much space the data files take
+    /// up and how much of it a merge would reclaim, per file too. The
+    /// counters are kept up to date as writes happen, so nothing is read.
+    pub fn stats(&self) -> Stats {
+        self.storage.read().unwrap().stats()
+    }
+
     pub fn list_keys(&self) -> Vec<Key> {
         self.storage.read().unwrap().list_keys()
     }
 
-    pub fn fold<F, Acc>(&self, fun: F, acc0: Acc) -> Result<Acc, DBError>
+    /// Returns a read-only view of the database as it is now. Writes and
+    /// merges can go on while it is in use.
+    pub fn snapshot(&self) -> Snapshot {
+        self.storage.read().unwrap().snapshot()
+    }
+
+    /// Returns every key/value pair, in key order. Pairs are read lazily from
+    /// a snapshot, so stopping early skips the rest of the database.
+    pub fn iter(&self) -> Iter {
+        self.snapshot().iter()
+    }
+
+    /// Returns every key, in order, without reading any value.
+    pub fn keys(&self) -> Keys {
+        self.snapshot().keys()
+    }
+
+    /// Returns every value, in key order.
+    pub fn values(&self) -> Values {
+        self.snapshot().values()
+    }
+
+    /// Returns the pairs with keys in `range`, in key order.
+    pub fn scan<R: RangeBounds<Key>>(&self, range: R) -> Iter {
+        self.snapshot().scan(range)
+    }
+
+    /// Returns the pairs with keys starting with `prefix`, in key order.
+    pub fn scan_prefix(&self, prefix: &Key) -> Iter {
+        self.snapshot().scan_prefix(prefix)
+    }
+
+    pub fn fold<F, Acc>(&self, mut fun: F, acc0: Acc) -> Result<Acc, DBError>
     where
-        F: Fn(Key, Value, Acc) -> Acc,
+        F: FnMut(Key, Value, Acc) -> Acc,
     {
-        self.storage.read().unwrap().fold(fun, acc0)
+        let mut acc = acc0;
+        for pair in self.iter() {
+            let (key, value) = pair?;
+            acc = fun(key, value, acc);
+        }
+
+        Ok(acc)
     }
 
+    /// Rewrites the live values of the data files into new ones and deletes
+    /// the old files. With [`Opts::merge_dead_ratio`] or
+    /// [`Opts::merge_dead_bytes`] set, only the files over a threshold are
+    /// merged, and nothing is done if there are none. Fails with
+    /// [`DBError::LockError`] while a background merge is running, and
+    /// reports a background merge that failed.
     pub fn merge(&mut self) -> Result<(), DBError> {
-        self.storage.write().unwrap().merge(self.sync_on_put)
+        self.check_open("merge")?;
+        if self.mutable {
+            if let Some(auto_merger) = &self.auto_merger {
+                auto_merger.check()?;
+            }
+            self.storage.write().unwrap().merge()
+        } else {
+            Err(DBError::OptionError(
+                "tried to merge in read-only access".to_string(),
+            ))
+        }
     }
 
+    /// Merges the data files `file_ids`, as numbered in
+    /// [`Stats::files`](stats::Stats::files), and leaves the others alone.
+    /// Deletions that shadow values in older files that are left alone are
+    /// kept. Like [`BitCask::merge`], fails while a background merge is
+    /// running and reports a background merge that failed.
+    pub fn merge_files(&mut self, file_ids: &[usize]) -> Result<(), DBError> {
+        self.check_open("merge")?;
+        if self.mutable {
+            if let Some(auto_merger) = &self.auto_merger {
+                auto_merger.check()?;
+            }
+            let file_ids = file_ids.iter().copied().collect();
+            self.storage.write().unwrap().merge_files(file_ids)
+        } else {
+            Err(DBError::OptionError(
+                "tried to merge in read-only access".to_string(),
+            ))
+        }
+    }
+
+    /// Does nothing in read-only access, as nothing is ever written.
     pub fn sync(&mut self) -> Result<(), DBError> {
-        self.storage.write().unwrap().sync()
+        if !self.mutable {
+            return Ok(());
+        }
+        self.storage.read().unwrap().sync()
+    }
+
+    /// Writes every live key/value pair to `writer` in the portable `format`
+    /// described in [`ExportFormat`], reading from a snapshot so that writes go on
+    /// meanwhile. Returns the number of pairs.
+    pub fn export<W: Write>(&self, writer: W, format: ExportFormat) -> Result<u64, DBError> {
+        export::export(&self.snapshot(), writer, format)
+    }
+
+    /// Puts every key/value pair of a stream written by
+    /// [`export`](Self::export), keeping their TTLs. Nothing is imported
+    /// unless the whole stream is read and its trailing checksum matches.
+    /// Returns the number of pairs in the stream.
+    pub fn import<R: Read>(&mut self, reader: R, format: ExportFormat) -> Result<u64, DBError> {
+        self.check_open("import")?;
+        if self.mutable {
+            let mut storage = self.storage.write().unwrap();
+            let count = storage.import(reader, format)?;
+            self.after_write(&storage)?;
+            Ok(count)
+        } else {
+            Err(DBError::OptionError(
+                "tried to import in read-only access".to_string(),
+            ))
+        }
     }
 
+    /// Writes a consistent copy of the database into `dest_dir`, which must
+    /// be empty or not exist, without stopping writes for longer than it
+    /// takes to seal the active file. Immutable data and hint files are
+    /// hard-linked where possible, and only the valid part of the newest
+    /// file is copied. The copy opens like any data directory, and a merge
+    /// running meanwhile does not affect it.
+    pub fn checkpoint<T: Into<PathBuf>>(&self, dest_dir: T) -> Result<(), DBError> {
+        self.check_open("checkpoint")?;
+        let checkpoint = self.storage.write().unwrap().checkpoint()?;
+        checkpoint.write(&dest_dir.into())
+    }
+
+    /// Reads every entry of every data file, checking its framing and
+    /// checksum, and checks that every key points at a valid value. Writes
+    /// and merges go on meanwhile, and what they add is not checked.
+    pub fn verify(&self) -> Result<VerifyReport, DBError> {
+        let verifier = self.storage.read().unwrap().verifier();
+        verifier.verify()
+    }
+
+    /// Catches a reader up with what the writer of the data directory has
+    /// written since the database was opened or last refreshed: new entries,
+    /// new data files and the outcome of merges. Only what was appended is
+    /// read, unless a merge replaced files. Snapshots taken before are not
+    /// affected. A writer is always up to date, so this does nothing there.
+    pub fn refresh(&mut self) -> Result<(), DBError> {
+        if self.mutable {
+            return Ok(());
+        }
+        if let Some(refresher) = &self.refresher {
+            refresher.check()?;
+        }
+        self.storage.write().unwrap().refresh()
+    }
+
+    /// Stops the background threads, syncs and lets other instances open the
+    /// data directory. Every write fails afterwards, while reads go on.
     pub fn close(&mut self) -> Result<(), DBError> {
-        self.sync()?;
+        if self.closed {
+            return Ok(());
+        }
+        if let Some(mut auto_merger) = self.auto_merger.take() {
+            auto_merger.stop();
+            auto_merger.check()?;
+        }
+        if let Some(mut flusher) = self.flusher.take() {
+            flusher.stop();
+            flusher.check()?;
+        }
+        if let Some(mut refresher) = self.refresher.take() {
+            refresher.stop();
+            refresher.check()?;
+        }
+        if self.mutable && self.sync_mode != SyncMode::Never {
+            self.sync()?;
+        }
+        self.storage.write().unwrap().unlock();
+        self.closed = true;
+        Ok(())
+    }
+
+    /// Fails once the database has been closed, when `action` would change
+    /// the data directory without holding its lock.
+    fn check_open(&self, action: &str) -> Result<(), DBError> {
+        if self.closed {
+            return Err(DBError::OptionError(format!(
+                "tried to {} after close",
+                action
+            )));
+        }
+        Ok(())
+    }
+
+    /// Wakes the flusher up once enough unsynced bytes have piled up, and
+    /// reports a failed background sync.
+    fn after_write(&self, storage: &Storage) -> Result<(), DBError> {
+        if let SyncMode::Periodic {
+            max_unsynced_bytes, ..
+        } = self.sync_mode
+        {
+            let flusher = self.flusher.as_ref().unwrap();
+            if storage.get_unsynced_bytes() >= max_unsynced_bytes {
+                flusher.wake_up();
+            }
+            flusher.check()?;
+        }
         Ok(())
     }
 }
 
+impl Drop for BitCask {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{opts::Opts, BitCask};
+    use std::{
+        collections::BTreeMap,
+        fs,
+        io::Write,
+        os::unix::fs::{FileExt, MetadataExt},
+        path::PathBuf,
+        thread,
+        time::Duration,
+    };
+
+    use super::{
+        backup::BackupRepository,
+        batch::WriteBatch,
+        export::ExportFormat,
+        opts::{CorruptionPolicy, Opts, SyncMode},
+        stats::SizeHistogram,
+        BitCask, Key, Value,
+    };
+    use crate::error::DBError;
+    use crc::{Crc, CRC_32_CKSUM};
     use rand::{self, Rng};
 
     #[test]
@@ -127,13 +493,1173 @@ mod tests {
         assert_eq!(res, None);
     }
 
+    #[test]
+    fn hint_file_test() {
+        let data_dir = generate_random_data_dir();
+        {
+            let mut tdb =
+                BitCask::open_with_opts(&data_dir, Opts::new(true, SyncMode::Always)).unwrap();
+            tdb.put(&vec![1], &vec![2]).unwrap();
+            tdb.put(&vec![3], &vec![4]).unwrap();
+            tdb.delete(&vec![3]).unwrap();
+        }
+
+        // The first reopen scans `0.tdb` and writes its hint, the second one
+        // loads the hint, and the third one falls back to the data file again.
+        for i in 0..3 {
+            if i == 2 {
+                fs::write(format!("{}/0.hint", data_dir), b"garbage").unwrap();
+            }
+            let tdb = BitCask::open_with_opts(&data_dir, Opts::new(true, SyncMode::Never)).unwrap();
+            assert_eq!(tdb.get(&vec![1]).unwrap(), Some(vec![2]));
+            assert_eq!(tdb.get(&vec![3]).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn merge_test() {
+        let data_dir = generate_random_data_dir();
+        let mut tdb =
+            BitCask::open_with_opts(&data_dir, Opts::new(true, SyncMode::Always)).unwrap();
+        for i in 0..100_u8 {
+            tdb.put(&vec![i % 10], &vec![i; 20_000]).unwrap();
+        }
+        tdb.delete(&vec![0]).unwrap();
+        tdb.merge().unwrap();
+        assert_eq!(tdb.get(&vec![9]).unwrap(), Some(vec![99; 20_000]));
+        drop(tdb);
+
+        // The replaced files are gone: nine 20KB values fit in one data file
+        // and the active file is empty.
+        assert_eq!(count_files(&data_dir, "tdb"), 2);
+
+        // Output of a merge that crashed before its manifest is ignored by
+        // readers and discarded by the next writer.
+        fs::write(format!("{}/100.merge", data_dir), b"partial").unwrap();
+        let tdb = BitCask::open(&data_dir).unwrap();
+        assert_eq!(tdb.get(&vec![9]).unwrap(), Some(vec![99; 20_000]));
+        drop(tdb);
+        assert!(fs::exists(format!("{}/100.merge", data_dir)).unwrap());
+        let tdb = BitCask::open_with_opts(&data_dir, Opts::new(true, SyncMode::Never)).unwrap();
+        assert!(!fs::exists(format!("{}/100.merge", data_dir)).unwrap());
+        assert_eq!(tdb.get(&vec![0]).unwrap(), None);
+        assert_eq!(tdb.get(&vec![9]).unwrap(), Some(vec![99; 20_000]));
+    }
+
+    #[test]
+    fn sync_mode_test() {
+        let mut tdb =
+            BitCask::open_with_opts(generate_random_data_dir(), Opts::new(true, SyncMode::Never))
+                .unwrap();
+        tdb.put(&vec![1], &vec![2]).unwrap();
+        assert!(tdb.storage.read().unwrap().get_unsynced_bytes() > 0);
+        tdb.sync().unwrap();
+        assert_eq!(tdb.storage.read().unwrap().get_unsynced_bytes(), 0);
+
+        let sync_mode = SyncMode::Periodic {
+            interval: Duration::from_secs(3600),
+            max_unsynced_bytes: 1,
+        };
+        let mut tdb =
+            BitCask::open_with_opts(generate_random_data_dir(), Opts::new(true, sync_mode))
+                .unwrap();
+        tdb.put(&vec![1], &vec![2]).unwrap();
+        // The byte limit wakes the flusher up long before the interval is over.
+        for _ in 0..100 {
+            if tdb.storage.read().unwrap().get_unsynced_bytes() == 0 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(tdb.storage.read().unwrap().get_unsynced_bytes(), 0);
+        tdb.close().unwrap();
+    }
+
+    #[test]
+    fn close_test() {
+        let data_dir = generate_random_data_dir();
+        let mut tdb =
+            BitCask::open_with_opts(&data_dir, Opts::new(true, SyncMode::Always)).unwrap();
+        tdb.put(&vec![1], &vec![2]).unwrap();
+        tdb.close().unwrap();
+        assert!(matches!(
+            tdb.put(&vec![1], &vec![3]),
+            Err(DBError::OptionError(_))
+        ));
+        assert!(matches!(tdb.delete(&vec![1]), Err(DBError::OptionError(_))));
+        let mut batch = WriteBatch::new();
+        batch.put(&vec![3], &vec![4]);
+        assert!(matches!(
+            tdb.write_batch(&batch),
+            Err(DBError::OptionError(_))
+        ));
+        assert!(matches!(tdb.merge(), Err(DBError::OptionError(_))));
+        assert_eq!(tdb.get(&vec![1]).unwrap(), Some(vec![2]));
+        tdb.close().unwrap();
+
+        // The lock was released, so another writer can take over.
+        let mut tdb2 =
+            BitCask::open_with_opts(&data_dir, Opts::new(true, SyncMode::Always)).unwrap();
+        tdb2.put(&vec![1], &vec![3]).unwrap();
+        drop(tdb);
+        drop(tdb2);
+        let tdb = BitCask::open(&data_dir).unwrap();
+        assert_eq!(tdb.get(&vec![1]).unwrap(), Some(vec![3]));
+        assert_eq!(tdb.get(&vec![3]).unwrap(), None);
+    }
+
+    #[test]
+    fn empty_value_test() {
+        let data_dir = generate_random_data_dir();
+        let mut tdb =
+            BitCask::open_with_opts(&data_dir, Opts::new(true, SyncMode::Always)).unwrap();
+        tdb.put(&vec![1], &vec![]).unwrap();
+        tdb.put(&vec![2], &vec![3]).unwrap();
+        tdb.delete(&vec![2]).unwrap();
+        assert_eq!(tdb.get(&vec![1]).unwrap(), Some(vec![]));
+        drop(tdb);
+
+        let tdb = BitCask::open(&data_dir).unwrap();
+        assert_eq!(tdb.get(&vec![1]).unwrap(), Some(vec![]));
+        assert_eq!(tdb.get(&vec![2]).unwrap(), None);
+    }
+
+    #[test]
+    fn torn_write_test() {
+        let data_dir = generate_random_data_dir();
+        let mut tdb =
+            BitCask::open_with_opts(&data_dir, Opts::new(true, SyncMode::Always)).unwrap();
+        tdb.put(&vec![1], &vec![2]).unwrap();
+        drop(tdb);
+        append_garbage(&format!("{}/0.tdb", data_dir));
+
+        let tdb = BitCask::open(&data_dir).unwrap();
+        assert_eq!(tdb.recovery_report().truncated_bytes, 5);
+        assert_eq!(tdb.get(&vec![1]).unwrap(), Some(vec![2]));
+    }
+
+    #[test]
+    fn corruption_policy_test() {
+        let data_dir = generate_random_data_dir();
+        for i in 0..2 {
+            let mut tdb =
+                BitCask::open_with_opts(&data_dir, Opts::new(true, SyncMode::Always)).unwrap();
+            tdb.put(&vec![i], &vec![i]).unwrap();
+        }
+        // `0.tdb` is no longer the newest file, so it is not simply truncated.
+        let corrupt = || {
+            fs::remove_file(format!("{}/0.hint", data_dir)).unwrap();
+            append_garbage(&format!("{}/0.tdb", data_dir));
+        };
+        corrupt();
+        let open = |policy| {
+            let mut opts = Opts::new(true, SyncMode::Always);
+            opts.corruption_policy(policy);
+            BitCask::open_with_opts(&data_dir, opts)
+        };
+
+        assert!(matches!(
+            open(CorruptionPolicy::Fail),
+            Err(DBError::DataError(_))
+        ));
+
+        let tdb = open(CorruptionPolicy::Skip).unwrap();
+        let report = tdb.recovery_report();
+        assert_eq!(report.corrupted_files.len(), 1);
+        assert_eq!(report.corrupted_files[0].file_id, 0);
+        assert_eq!(report.corrupted_files[0].ignored_bytes, 5);
+        assert_eq!(tdb.get(&vec![0]).unwrap(), Some(vec![0]));
+        drop(tdb);
+
+        corrupt();
+        let tdb = open(CorruptionPolicy::Quarantine).unwrap();
+        let report = tdb.recovery_report();
+        let quarantine_path = report.corrupted_files[0].quarantine_path.as_ref();
+        assert_eq!(fs::read(quarantine_path.unwrap()).unwrap().len(), 10);
+        assert_eq!(tdb.get(&vec![0]).unwrap(), Some(vec![0]));
+        assert_eq!(tdb.get(&vec![1]).unwrap(), Some(vec![1]));
+    }
+
+    #[test]
+    fn mid_file_corruption_test() {
+        let data_dir = generate_random_data_dir();
+        let mut tdb =
+            BitCask::open_with_opts(&data_dir, Opts::new(true, SyncMode::Always)).unwrap();
+        for i in 0..3 {
+            tdb.put(&vec![i], &vec![i]).unwrap();
+        }
+        drop(tdb);
+        BitCask::open_with_opts(&data_dir, Opts::new(true, SyncMode::Always)).unwrap();
+        // Flips the value of the second entry of `0.tdb`, which is no longer
+        // the newest file.
+        let entry_sz = 35;
+        let offset = 8 + entry_sz;
+        let corrupt = || {
+            fs::remove_file(format!("{}/0.hint", data_dir)).unwrap();
+            let file = fs::OpenOptions::new()
+                .write(true)
+                .open(format!("{}/0.tdb", data_dir))
+                .unwrap();
+            file.write_all_at(&[0xff], offset + entry_sz - 1).unwrap();
+        };
+        let open = |policy| {
+            let mut opts = Opts::new(true, SyncMode::Always);
+            opts.corruption_policy(policy);
+            BitCask::open_with_opts(&data_dir, opts).unwrap()
+        };
+
+        corrupt();
+        let tdb = open(CorruptionPolicy::Skip);
+        let report = tdb.recovery_report();
+        assert_eq!(report.corrupted_files.len(), 1);
+        assert_eq!(report.corrupted_files[0].offset, offset);
+        assert_eq!(report.corrupted_files[0].ignored_bytes, entry_sz);
+        assert_eq!(tdb.get(&vec![0]).unwrap(), Some(vec![0]));
+        assert_eq!(tdb.get(&vec![1]).unwrap(), None);
+        assert_eq!(tdb.get(&vec![2]).unwrap(), Some(vec![2]));
+        drop(tdb);
+
+        corrupt();
+        let tdb = open(CorruptionPolicy::Quarantine);
+        let report = tdb.recovery_report();
+        assert_eq!(report.corrupted_files.len(), 1);
+        let quarantine_path = report.corrupted_files[0].quarantine_path.as_ref();
+        assert_eq!(
+            fs::read(quarantine_path.unwrap()).unwrap().len() as u64,
+            entry_sz
+        );
+        assert_eq!(tdb.get(&vec![0]).unwrap(), Some(vec![0]));
+        assert_eq!(tdb.get(&vec![2]).unwrap(), Some(vec![2]));
+        drop(tdb);
+
+        // The file was rewritten without the damaged entry.
+        fs::remove_file(format!("{}/0.hint", data_dir)).unwrap();
+        let tdb = open(CorruptionPolicy::Fail);
+        assert!(tdb.recovery_report().corrupted_files.is_empty());
+        assert_eq!(tdb.get(&vec![0]).unwrap(), Some(vec![0]));
+        assert_eq!(tdb.get(&vec![1]).unwrap(), None);
+        assert_eq!(tdb.get(&vec![2]).unwrap(), Some(vec![2]));
+    }
+
+    #[test]
+    fn format_version_test() {
+        // A headerless file as written before format versions existed.
+        let data_dir = generate_random_data_dir();
+        fs::create_dir_all(&data_dir).unwrap();
+        let mut entry = vec![];
+        entry.extend_from_slice(&1_u64.to_be_bytes());
+        entry.extend_from_slice(&2_u64.to_be_bytes());
+        entry.extend_from_slice(&[1, 4, 5]);
+        let checksum = Crc::<u32>::new(&CRC_32_CKSUM).checksum(&entry);
+        let mut legacy_file = checksum.to_be_bytes().to_vec();
+        legacy_file.extend(entry);
+        fs::write(format!("{}/0.tdb", data_dir), legacy_file).unwrap();
+
+        let mut tdb =
+            BitCask::open_with_opts(&data_dir, Opts::new(true, SyncMode::Always)).unwrap();
+        assert_eq!(tdb.get(&vec![1]).unwrap(), Some(vec![4, 5]));
+        tdb.put(&vec![2], &vec![6]).unwrap();
+        drop(tdb);
+        let tdb = BitCask::open(&data_dir).unwrap();
+        assert_eq!(tdb.get(&vec![1]).unwrap(), Some(vec![4, 5]));
+        assert_eq!(tdb.get(&vec![2]).unwrap(), Some(vec![6]));
+        drop(tdb);
+
+        // A file from a newer version of tdb.
+        let data_dir = generate_random_data_dir();
+        fs::create_dir_all(&data_dir).unwrap();
+        fs::write(format!("{}/0.tdb", data_dir), b"TDB\0\0\0\0\xff").unwrap();
+        let res = BitCask::open(&data_dir);
+        assert!(matches!(res, Err(DBError::VersionError(_))));
+    }
+
+    #[test]
+    fn write_batch_test() {
+        let data_dir = generate_random_data_dir();
+        let mut tdb =
+            BitCask::open_with_opts(&data_dir, Opts::new(true, SyncMode::Always)).unwrap();
+        tdb.put(&vec![0], &vec![0]).unwrap();
+        // Three 400KB values do not fit in one data file, so the batch spans
+        // `0.tdb` and `1.tdb`.
+        let mut batch = WriteBatch::new();
+        for i in 1..4_u8 {
+            batch.put(&vec![i], &vec![i; 400_000]);
+        }
+        batch.delete(&vec![0]);
+        tdb.write_batch(&batch).unwrap();
+        assert_eq!(tdb.get(&vec![0]).unwrap(), None);
+        drop(tdb);
+
+        let tdb = BitCask::open(&data_dir).unwrap();
+        assert_eq!(tdb.get(&vec![0]).unwrap(), None);
+        assert_eq!(tdb.get(&vec![3]).unwrap(), Some(vec![3; 400_000]));
+        drop(tdb);
+
+        // Without its last entries, nothing of the batch is replayed.
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .open(format!("{}/1.tdb", data_dir))
+            .unwrap();
+        file.set_len(8).unwrap();
+        let tdb = BitCask::open(&data_dir).unwrap();
+        assert_eq!(tdb.get(&vec![0]).unwrap(), Some(vec![0]));
+        assert_eq!(tdb.get(&vec![1]).unwrap(), None);
+    }
+
+    #[test]
+    fn scan_test() {
+        let mut tdb = generate_random_bitcask_instance();
+        for i in 0..200_u8 {
+            tdb.put(&vec![b'a', i], &vec![i]).unwrap();
+        }
+        tdb.put(&vec![b'b'], &vec![]).unwrap();
+        tdb.put(&vec![0xff, 0xff], &vec![]).unwrap();
+        fn keys(iter: impl Iterator<Item = Result<(Key, Value), DBError>>) -> Vec<Key> {
+            iter.map(|pair| pair.unwrap().0).collect()
+        }
+
+        let all = keys(tdb.scan(..));
+        assert_eq!(all.len(), 202);
+        assert!(all.windows(2).all(|w| w[0] < w[1]));
+        let mut rev = keys(tdb.scan(..).rev());
+        rev.reverse();
+        assert_eq!(rev, all);
+
+        let range = tdb.scan(vec![b'a', 10]..=vec![b'a', 100]);
+        assert_eq!(keys(range), all[10..=100]);
+        assert_eq!(keys(tdb.scan(vec![b'a', 100]..vec![b'a', 100])).len(), 0);
+        assert_eq!(keys(tdb.scan_prefix(&vec![b'a'])), all[..200]);
+        assert_eq!(keys(tdb.scan_prefix(&vec![0xff])), vec![vec![0xff, 0xff]]);
+
+        // Both ends meet in the middle without skipping or repeating pairs.
+        let mut iter = tdb.scan_prefix(&vec![b'a']);
+        let mut seen = vec![];
+        while let Some(front) = iter.next() {
+            seen.push(front.unwrap().0);
+            if let Some(back) = iter.next_back() {
+                seen.push(back.unwrap().0);
+            }
+        }
+        seen.sort();
+        assert_eq!(seen, all[..200]);
+    }
+
+    #[test]
+    fn iterator_test() {
+        let mut tdb = generate_random_bitcask_instance();
+        for i in (0..100_u8).rev() {
+            tdb.put(&vec![i], &vec![i, i]).unwrap();
+        }
+        tdb.delete(&vec![50]).unwrap();
+
+        let keys: Vec<_> = tdb.keys().collect();
+        assert_eq!(keys.len(), 99);
+        assert_eq!(keys[0], vec![0]);
+        assert_eq!(tdb.keys().next_back(), Some(vec![99]));
+        let values: Vec<_> = tdb.values().take(2).map(|v| v.unwrap()).collect();
+        assert_eq!(values, vec![vec![0, 0], vec![1, 1]]);
+        let found = tdb
+            .iter()
+            .map(|pair| pair.unwrap())
+            .find(|(k, _)| k[0] > 49);
+        assert_eq!(found, Some((vec![51], vec![51, 51])));
+
+        let mut visited = 0;
+        let sum = tdb
+            .fold(
+                |_, value, acc| {
+                    visited += 1;
+                    acc + value[0] as u32
+                },
+                0,
+            )
+            .unwrap();
+        assert_eq!(visited, 99);
+        assert_eq!(sum, (0..100).sum::<u32>() - 50);
+    }
+
+    #[test]
+    fn snapshot_test() {
+        let data_dir = generate_random_data_dir();
+        let mut tdb =
+            BitCask::open_with_opts(&data_dir, Opts::new(true, SyncMode::Always)).unwrap();
+        for i in 0..10_u8 {
+            tdb.put(&vec![i], &vec![i; 200_000]).unwrap();
+        }
+        let snapshot = tdb.snapshot();
+        let iter = tdb.iter();
+        tdb.put(&vec![0], &vec![]).unwrap();
+        tdb.delete(&vec![9]).unwrap();
+        tdb.put(&vec![10], &vec![]).unwrap();
+        assert_eq!(snapshot.get(&vec![0]).unwrap(), Some(vec![0; 200_000]));
+        assert_eq!(snapshot.get(&vec![10]).unwrap(), None);
+        assert_eq!(snapshot.keys().count(), 10);
+        assert_eq!(tdb.get(&vec![0]).unwrap(), Some(vec![]));
+
+        // The files replaced by the merge stay until the snapshots are gone.
+        tdb.merge().unwrap();
+        assert!(count_files(&data_dir, "retired") > 0);
+        assert_eq!(iter.last().unwrap().unwrap(), (vec![9], vec![9; 200_000]));
+        assert_eq!(snapshot.get(&vec![9]).unwrap(), Some(vec![9; 200_000]));
+        drop(snapshot);
+        assert_eq!(count_files(&data_dir, "retired"), 0);
+        assert_eq!(tdb.get(&vec![9]).unwrap(), None);
+    }
+
+    #[test]
+    fn transaction_test() {
+        let mut tdb = generate_random_bitcask_instance();
+        tdb.put(&vec![1], &vec![100]).unwrap();
+        tdb.put(&vec![2], &vec![0]).unwrap();
+
+        let transfer = |tdb: &BitCask| {
+            let mut txn = tdb.transaction();
+            let from = txn.get(&vec![1]).unwrap().unwrap()[0];
+            let to = txn.get(&vec![2]).unwrap().unwrap()[0];
+            txn.put(&vec![1], &vec![from - 10]);
+            txn.put(&vec![2], &vec![to + 10]);
+            assert_eq!(txn.get(&vec![2]).unwrap(), Some(vec![to + 10]));
+            txn
+        };
+        let txn = transfer(&tdb);
+        tdb.commit(txn).unwrap();
+        assert_eq!(tdb.get(&vec![1]).unwrap(), Some(vec![90]));
+        assert_eq!(tdb.get(&vec![2]).unwrap(), Some(vec![10]));
+
+        // A write to a key that was read makes the commit fail, even if it
+        // left the same value behind. Writes to other keys do not.
+        let txn = transfer(&tdb);
+        tdb.put(&vec![3], &vec![]).unwrap();
+        tdb.put(&vec![2], &vec![10]).unwrap();
+        let res = tdb.commit(txn);
+        assert!(matches!(res, Err(DBError::ConflictError(_))));
+        assert_eq!(tdb.get(&vec![1]).unwrap(), Some(vec![90]));
+        let txn = transfer(&tdb);
+        tdb.put(&vec![3], &vec![]).unwrap();
+        tdb.commit(txn).unwrap();
+        assert_eq!(tdb.get(&vec![1]).unwrap(), Some(vec![80]));
+
+        // The same goes for a key that was read as absent, then written and
+        // deleted again, and for one deleted again while absent.
+        let mut txn = tdb.transaction();
+        assert_eq!(txn.get(&vec![4]).unwrap(), None);
+        txn.put(&vec![4], &vec![1]);
+        tdb.put(&vec![4], &vec![2]).unwrap();
+        tdb.delete(&vec![4]).unwrap();
+        let res = tdb.commit(txn);
+        assert!(matches!(res, Err(DBError::ConflictError(_))));
+        let mut txn = tdb.transaction();
+        assert_eq!(txn.get(&vec![4]).unwrap(), None);
+        txn.put(&vec![4], &vec![1]);
+        tdb.delete(&vec![4]).unwrap();
+        let res = tdb.commit(txn);
+        assert!(matches!(res, Err(DBError::ConflictError(_))));
+        assert_eq!(tdb.get(&vec![4]).unwrap(), None);
+
+        // Once a merge lets go of the deletions, a key read as absent is
+        // taken as written.
+        let mut txn = tdb.transaction();
+        assert_eq!(txn.get(&vec![5]).unwrap(), None);
+        txn.put(&vec![5], &vec![1]);
+        tdb.merge().unwrap();
+        let res = tdb.commit(txn);
+        assert!(matches!(res, Err(DBError::ConflictError(_))));
+        let mut txn = tdb.transaction();
+        assert_eq!(txn.get(&vec![5]).unwrap(), None);
+        txn.put(&vec![5], &vec![1]);
+        tdb.commit(txn).unwrap();
+        assert_eq!(tdb.get(&vec![5]).unwrap(), Some(vec![1]));
+    }
+
+    #[test]
+    fn ttl_test() {
+        let data_dir = generate_random_data_dir();
+        let mut tdb =
+            BitCask::open_with_opts(&data_dir, Opts::new(true, SyncMode::Always)).unwrap();
+        tdb.put(&vec![1], &vec![1]).unwrap();
+        tdb.put_with_ttl(&vec![1], &vec![2], Duration::from_millis(300))
+            .unwrap();
+        tdb.put_with_ttl(&vec![2], &vec![2], Duration::from_secs(3600))
+            .unwrap();
+        assert_eq!(tdb.get(&vec![1]).unwrap(), Some(vec![2]));
+        drop(tdb);
+
+        let tdb = BitCask::open(&data_dir).unwrap();
+        assert_eq!(tdb.get(&vec![1]).unwrap(), Some(vec![2]));
+        thread::sleep(Duration::from_millis(300));
+        // An expired value does not bring back the one it overwrote.
+        assert_eq!(tdb.get(&vec![1]).unwrap(), None);
+        assert_eq!(tdb.keys().collect::<Vec<_>>(), vec![vec![2]]);
+        assert_eq!(tdb.list_keys(), vec![vec![2]]);
+        drop(tdb);
+
+        let mut tdb =
+            BitCask::open_with_opts(&data_dir, Opts::new(true, SyncMode::Always)).unwrap();
+        assert_eq!(tdb.get(&vec![1]).unwrap(), None);
+        tdb.merge().unwrap();
+        assert_eq!(tdb.get(&vec![2]).unwrap(), Some(vec![2]));
+        drop(tdb);
+        let tdb = BitCask::open(&data_dir).unwrap();
+        assert_eq!(tdb.get(&vec![1]).unwrap(), None);
+        assert_eq!(tdb.get(&vec![2]).unwrap(), Some(vec![2]));
+    }
+
+    #[test]
+    fn file_rotation_test() {
+        let data_dir = generate_random_data_dir();
+        let mut opts = Opts::new(true, SyncMode::Never);
+        opts.max_file_size(1_000);
+        opts.max_file_age(Some(Duration::from_millis(200)));
+        let mut tdb = BitCask::open_with_opts(&data_dir, opts).unwrap();
+        // Two 400 byte values fit in a file, and a 2KB value gets one of its own.
+        for i in 0..6_u8 {
+            tdb.put(&vec![i], &vec![i; 400]).unwrap();
+        }
+        assert_eq!(count_files(&data_dir, "tdb"), 3);
+        tdb.put(&vec![6], &vec![6; 2_000]).unwrap();
+        tdb.put(&vec![7], &vec![]).unwrap();
+        assert_eq!(count_files(&data_dir, "tdb"), 5);
+        thread::sleep(Duration::from_millis(200));
+        tdb.put(&vec![8], &vec![]).unwrap();
+        assert_eq!(count_files(&data_dir, "tdb"), 6);
+
+        // The merge output is split the same way, next to an empty active
+        // file.
+        tdb.merge().unwrap();
+        assert_eq!(count_files(&data_dir, "tdb"), 6);
+        assert_eq!(tdb.get(&vec![6]).unwrap(), Some(vec![6; 2_000]));
+    }
+
+    #[test]
+    fn lock_test() {
+        let data_dir = generate_random_data_dir();
+        let mut tdb = BitCask::open_with_opts(&data_dir, Opts::new(true, SyncMode::Never)).unwrap();
+        let res = BitCask::open_with_opts(&data_dir, Opts::new(true, SyncMode::Never));
+        assert!(matches!(res, Err(DBError::LockError(_))));
+        assert!(matches!(
+            BitCask::open(&data_dir),
+            Err(DBError::LockError(_))
+        ));
+        tdb.close().unwrap();
+
+        // Readers share the directory, and a writer waits for them.
+        let reader = BitCask::open(&data_dir).unwrap();
+        drop(BitCask::open(&data_dir).unwrap());
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            drop(reader);
+        });
+        let mut opts = Opts::new(true, SyncMode::Never);
+        opts.lock_timeout(Some(Duration::from_secs(10)));
+        BitCask::open_with_opts(&data_dir, opts).unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn follow_test() {
+        let data_dir = generate_random_data_dir();
+        let mut opts = Opts::new(true, SyncMode::Never);
+        opts.max_file_size(1_000);
+        drop(BitCask::open_with_opts(&data_dir, opts).unwrap());
+
+        // Following readers and the writer do not lock each other out.
+        let mut follow_opts = Opts::new(false, SyncMode::Never);
+        follow_opts.follow(true);
+        let mut reader = BitCask::open_with_opts(&data_dir, follow_opts).unwrap();
+        let mut opts = Opts::new(true, SyncMode::Never);
+        opts.max_file_size(1_000);
+        let mut tdb = BitCask::open_with_opts(&data_dir, opts).unwrap();
+        let mut follow_opts = Opts::new(false, SyncMode::Never);
+        follow_opts.follow(true);
+        follow_opts.refresh_interval(Some(Duration::from_millis(10)));
+        let auto_reader = BitCask::open_with_opts(&data_dir, follow_opts).unwrap();
+
+        tdb.put(&vec![0], &vec![0]).unwrap();
+        assert_eq!(reader.get(&vec![0]).unwrap(), None);
+        reader.refresh().unwrap();
+        assert_eq!(reader.get(&vec![0]).unwrap(), Some(vec![0]));
+
+        // Appends to the same file, new files and batches spanning them.
+        let mut batch = WriteBatch::new();
+        for i in 1..6_u8 {
+            batch.put(&vec![i], &vec![i; 400]);
+        }
+        batch.delete(&vec![0]);
+        tdb.write_batch(&batch).unwrap();
+        reader.refresh().unwrap();
+        assert_eq!(reader.get(&vec![0]).unwrap(), None);
+        assert_eq!(reader.get(&vec![5]).unwrap(), Some(vec![5; 400]));
+
+        // A merge replaces every file the reader knows of.
+        let snapshot = reader.snapshot();
+        tdb.delete(&vec![1]).unwrap();
+        tdb.merge().unwrap();
+        tdb.put(&vec![6], &vec![6]).unwrap();
+        reader.refresh().unwrap();
+        assert_eq!(
+            reader.list_keys(),
+            vec![vec![2], vec![3], vec![4], vec![5], vec![6]]
+        );
+        assert_eq!(reader.get(&vec![5]).unwrap(), Some(vec![5; 400]));
+        assert_eq!(snapshot.get(&vec![1]).unwrap(), Some(vec![1; 400]));
+
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(auto_reader.get(&vec![6]).unwrap(), Some(vec![6]));
+        assert_eq!(auto_reader.get(&vec![1]).unwrap(), None);
+    }
+
+    #[test]
+    fn checkpoint_test() {
+        let data_dir = generate_random_data_dir();
+        let mut opts = Opts::new(true, SyncMode::Never);
+        opts.max_file_size(1_000);
+        let mut tdb = BitCask::open_with_opts(&data_dir, opts).unwrap();
+        for i in 0..6_u8 {
+            tdb.put(&vec![i], &vec![i; 400]).unwrap();
+        }
+        tdb.delete(&vec![0]).unwrap();
+
+        let checkpoint_dir = generate_random_data_dir();
+        tdb.checkpoint(&checkpoint_dir).unwrap();
+        assert!(tdb.checkpoint(&checkpoint_dir).is_err());
+        tdb.put(&vec![6], &vec![6]).unwrap();
+        let checkpoint = BitCask::open(&checkpoint_dir).unwrap();
+        assert_eq!(checkpoint.list_keys().len(), 5);
+        assert_eq!(checkpoint.get(&vec![5]).unwrap(), Some(vec![5; 400]));
+        assert_eq!(checkpoint.get(&vec![6]).unwrap(), None);
+        assert_eq!(
+            count_files(&checkpoint_dir, "hint"),
+            count_files(&checkpoint_dir, "tdb") - 1
+        );
+        drop(checkpoint);
+
+        // A merge may delete the files before they are written out.
+        let checkpoint = tdb.storage.write().unwrap().checkpoint().unwrap();
+        tdb.merge().unwrap();
+        let checkpoint_dir = generate_random_data_dir();
+        checkpoint.write(checkpoint_dir.as_ref()).unwrap();
+        let checkpoint = BitCask::open(&checkpoint_dir).unwrap();
+        assert_eq!(checkpoint.list_keys().len(), 6);
+        assert_eq!(checkpoint.get(&vec![1]).unwrap(), Some(vec![1; 400]));
+    }
+
+    #[test]
+    fn backup_test() {
+        let data_dir = generate_random_data_dir();
+        let mut opts = Opts::new(true, SyncMode::Never);
+        opts.max_file_size(1_000);
+        let mut tdb = BitCask::open_with_opts(&data_dir, opts).unwrap();
+        let repo_dir = generate_random_data_dir();
+        let repo = BackupRepository::open(&repo_dir).unwrap();
+        for i in 0..4_u8 {
+            tdb.put(&vec![i], &vec![i; 400]).unwrap();
+        }
+        let first = repo.create_backup(&tdb).unwrap();
+        tdb.delete(&vec![0]).unwrap();
+        tdb.put(&vec![4], &vec![4; 400]).unwrap();
+        let second = repo.create_backup(&tdb).unwrap();
+        assert_eq!(repo.list_backups().unwrap(), vec![first.clone(), second]);
+
+        // Only the files that changed are stored again.
+        let files_dir = format!("{}/files", repo_dir);
+        let stored = count_files(&files_dir, "tdb");
+        assert!(stored < first.files + repo.list_backups().unwrap()[1].files);
+
+        let restore_dir = generate_random_data_dir();
+        repo.restore(first.id, &restore_dir).unwrap();
+        let restored = BitCask::open(&restore_dir).unwrap();
+        assert_eq!(restored.get(&vec![0]).unwrap(), Some(vec![0; 400]));
+        assert_eq!(restored.get(&vec![4]).unwrap(), None);
+
+        // Files only the pruned backup used are deleted.
+        repo.prune(1).unwrap();
+        assert_eq!(repo.list_backups().unwrap().len(), 1);
+        assert!(count_files(&files_dir, "tdb") < stored);
+        assert!(repo.restore(first.id, generate_random_data_dir()).is_err());
+        let restore_dir = generate_random_data_dir();
+        repo.restore(first.id + 1, &restore_dir).unwrap();
+        let restored = BitCask::open(&restore_dir).unwrap();
+        assert_eq!(restored.get(&vec![0]).unwrap(), None);
+        assert_eq!(restored.get(&vec![4]).unwrap(), Some(vec![4; 400]));
+
+        // The repository holds copies, which a damaged data file leaves
+        // alone. The checksum recorded when the file was sealed names it, so
+        // it is not read again.
+        for path in fs::read_dir(&files_dir).unwrap() {
+            assert_eq!(path.unwrap().metadata().unwrap().nlink(), 1);
+        }
+        let stored = count_files(&files_dir, "tdb");
+        let path = format!("{}/0.tdb", data_dir);
+        let file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+        let file_sz = file.metadata().unwrap().len();
+        file.write_all_at(&[0xff], file_sz - 1).unwrap();
+        let third = repo.create_backup(&tdb).unwrap();
+        assert_eq!(count_files(&files_dir, "tdb"), stored);
+        let restore_dir = generate_random_data_dir();
+        repo.restore(third.id, &restore_dir).unwrap();
+        let restored = BitCask::open(&restore_dir).unwrap();
+        assert_eq!(restored.get(&vec![1]).unwrap(), Some(vec![1; 400]));
+
+        // A file rewritten under the same id is stored anew.
+        file.set_len(file_sz - 1).unwrap();
+        fs::remove_file(format!("{}/0.hint", data_dir)).unwrap();
+        repo.create_backup(&tdb).unwrap();
+        assert_eq!(count_files(&files_dir, "tdb"), stored + 1);
+    }
+
+    #[test]
+    fn export_test() {
+        let mut tdb = generate_random_bitcask_instance();
+        for i in 0..8_u8 {
+            tdb.put(&vec![i], &vec![i; 300_000]).unwrap();
+        }
+        tdb.put_with_ttl(&vec![8], &vec![], Duration::from_secs(3600))
+            .unwrap();
+        tdb.delete(&vec![0]).unwrap();
+
+        for format in [ExportFormat::Binary, ExportFormat::JsonLines] {
+            let mut stream = vec![];
+            assert_eq!(tdb.export(&mut stream, format).unwrap(), 8);
+
+            // A stream cut short or damaged leaves no trace, even though the
+            // pairs do not fit in one chunk.
+            let data_dir = generate_random_data_dir();
+            let mut imported =
+                BitCask::open_with_opts(&data_dir, Opts::new(true, SyncMode::Never)).unwrap();
+            let truncated = &stream[..stream.len() - 10];
+            assert!(imported.import(truncated, format).is_err());
+            let mut damaged = stream.clone();
+            damaged[stream.len() / 2] ^= 1;
+            assert!(imported.import(damaged.as_slice(), format).is_err());
+            assert!(imported.list_keys().is_empty());
+            drop(imported);
+            let mut imported =
+                BitCask::open_with_opts(&data_dir, Opts::new(true, SyncMode::Never)).unwrap();
+            assert!(imported.list_keys().is_empty());
+
+            assert_eq!(imported.import(stream.as_slice(), format).unwrap(), 8);
+            drop(imported);
+            let imported = BitCask::open(&data_dir).unwrap();
+            assert_eq!(imported.list_keys(), tdb.list_keys());
+            assert_eq!(imported.get(&vec![7]).unwrap(), Some(vec![7; 300_000]));
+            let expiry = |tdb: &BitCask| {
+                let storage = tdb.storage.read().unwrap();
+                storage
+                    .snapshot()
+                    .get_keydir()
+                    .get(&vec![8])
+                    .unwrap()
+                    .expiry
+            };
+            assert_eq!(expiry(&imported), expiry(&tdb));
+        }
+    }
+
+    #[test]
+    fn read_only_test() {
+        let data_dir = generate_random_data_dir();
+        {
+            let mut tdb =
+                BitCask::open_with_opts(&data_dir, Opts::new(true, SyncMode::Always)).unwrap();
+            tdb.put(&vec![1], &vec![2]).unwrap();
+        }
+        // A torn tail, no hint and no lock file: a writer would fix all three.
+        append_garbage(&format!("{}/0.tdb", data_dir));
+        fs::remove_file(format!("{}/LOCK", data_dir)).unwrap();
+        let before = read_dir_contents(&data_dir);
+
+        let mut tdb = BitCask::open(&data_dir).unwrap();
+        assert_eq!(tdb.get(&vec![1]).unwrap(), Some(vec![2]));
+        assert_eq!(tdb.recovery_report().truncated_bytes, 5);
+        assert!(matches!(
+            tdb.put(&vec![3], &vec![4]),
+            Err(DBError::OptionError(_))
+        ));
+        assert!(matches!(tdb.merge(), Err(DBError::OptionError(_))));
+        tdb.sync().unwrap();
+        tdb.close().unwrap();
+        assert_eq!(read_dir_contents(&data_dir), before);
+
+        // Nor is a missing directory created.
+        let missing_dir = generate_random_data_dir();
+        assert!(BitCask::open(&missing_dir).is_err());
+        assert!(!fs::exists(&missing_dir).unwrap());
+    }
+
+    #[test]
+    fn verify_test() {
+        let data_dir = generate_random_data_dir();
+        for i in 0..2 {
+            let mut tdb =
+                BitCask::open_with_opts(&data_dir, Opts::new(true, SyncMode::Always)).unwrap();
+            for j in 0..5 {
+                tdb.put(&vec![i, j], &vec![j; 10]).unwrap();
+            }
+        }
+        let report = BitCask::open(&data_dir).unwrap().verify().unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.files, 2);
+        assert_eq!(report.entries, 10);
+
+        // Flip the last byte of the last value in `0.tdb`, which its hint
+        // file still points at, and add a torn entry after it.
+        let path = format!("{}/0.tdb", data_dir);
+        let mut contents = fs::read(&path).unwrap();
+        let file_sz = contents.len() as u64;
+        *contents.last_mut().unwrap() ^= 0xff;
+        fs::write(&path, contents).unwrap();
+        append_garbage(&path);
+
+        let report = BitCask::open(&data_dir).unwrap().verify().unwrap();
+        assert!(!report.is_ok());
+        assert_eq!(report.entries, 9);
+        assert_eq!(report.bad_entries.len(), 2);
+        let bad_entry = &report.bad_entries[0];
+        assert_eq!(bad_entry.file_id, 0);
+        assert_eq!(bad_entry.key, Some(vec![0, 4]));
+        assert_eq!(bad_entry.offset + bad_entry.len, file_sz);
+        assert_eq!(report.bad_entries[1].offset, file_sz);
+        assert_eq!(report.bad_entries[1].len, 5);
+        assert_eq!(report.bad_entries[1].key, None);
+        assert_eq!(report.bad_keys.len(), 1);
+        assert_eq!(report.bad_keys[0].key, vec![0, 4]);
+    }
+
+    #[test]
+    fn repair_test() {
+        let data_dir = generate_random_data_dir();
+        let mut tdb =
+            BitCask::open_with_opts(&data_dir, Opts::new(true, SyncMode::Always)).unwrap();
+        for i in 0..5 {
+            tdb.put(&vec![i], &vec![i; 10]).unwrap();
+        }
+        drop(tdb);
+        let mut tdb =
+            BitCask::open_with_opts(&data_dir, Opts::new(true, SyncMode::Always)).unwrap();
+        tdb.put(&vec![0], &vec![5; 10]).unwrap();
+        drop(tdb);
+
+        // Entries are 44 bytes long. Damage the value of the third one, and
+        // the header of the fourth.
+        let path = format!("{}/0.tdb", data_dir);
+        let mut contents = fs::read(&path).unwrap();
+        contents[8 + 2 * 44 + 39] ^= 0xff;
+        contents[8 + 3 * 44 + 20] ^= 0xff;
+        fs::write(&path, contents).unwrap();
+        fs::remove_file(format!("{}/0.hint", data_dir)).unwrap();
+        assert!(BitCask::open(&data_dir).is_err());
+
+        let report = BitCask::repair(&data_dir).unwrap();
+        assert_eq!(report.files, 2);
+        assert_eq!(report.entries, 4);
+        assert_eq!(report.quarantined.len(), 1);
+        let span = &report.quarantined[0];
+        assert_eq!((span.file_id, span.offset, span.len), (0, 96, 88));
+        assert_eq!(fs::read(&span.path).unwrap().len(), 88);
+        // The second key was written before the damage and not since, and
+        // the third one was in it. The first one was written again later.
+        assert_eq!(report.keys_at_risk, vec![vec![1], vec![2]]);
+        assert!(report.report_path.unwrap().exists());
+
+        let tdb = BitCask::open(&data_dir).unwrap();
+        assert!(tdb.verify().unwrap().is_ok());
+        assert_eq!(tdb.get(&vec![0]).unwrap(), Some(vec![5; 10]));
+        assert_eq!(tdb.get(&vec![2]).unwrap(), None);
+        assert_eq!(tdb.get(&vec![3]).unwrap(), None);
+        assert_eq!(tdb.get(&vec![4]).unwrap(), Some(vec![4; 10]));
+        drop(tdb);
+        assert!(BitCask::repair(&data_dir).unwrap().quarantined.is_empty());
+
+        // A span too short to hold an entry puts no key at risk.
+        append_garbage(&path);
+        let report = BitCask::repair(&data_dir).unwrap();
+        assert_eq!(report.quarantined.len(), 1);
+        assert!(report.keys_at_risk.is_empty());
+
+        // Resyncing through a value that seems to frame large entries at
+        // many offsets reads none of them.
+        let data_dir = generate_random_data_dir();
+        let mut tdb =
+            BitCask::open_with_opts(&data_dir, Opts::new(true, SyncMode::Always)).unwrap();
+        tdb.put(&vec![6], &(1_u64 << 17).to_be_bytes().repeat(1 << 17))
+            .unwrap();
+        tdb.put(&vec![7], &vec![7]).unwrap();
+        drop(tdb);
+        let path = format!("{}/0.tdb", data_dir);
+        let file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.write_all_at(&[0xff], 8 + 20).unwrap();
+        let report = BitCask::repair(&data_dir).unwrap();
+        assert_eq!(report.entries, 1);
+        assert_eq!(report.quarantined[0].len, 33 + 1 + (1 << 20));
+        let tdb = BitCask::open(&data_dir).unwrap();
+        assert_eq!(tdb.get(&vec![6]).unwrap(), None);
+        assert_eq!(tdb.get(&vec![7]).unwrap(), Some(vec![7]));
+    }
+
+    #[test]
+    fn stats_test() {
+        let data_dir = generate_random_data_dir();
+        let mut tdb = BitCask::open_with_opts(&data_dir, Opts::new(true, SyncMode::Never)).unwrap();
+        tdb.put(&vec![1], &vec![1; 10]).unwrap();
+        tdb.put(&vec![2], &vec![2; 10]).unwrap();
+        tdb.put(&vec![1], &vec![3; 10]).unwrap();
+        tdb.delete(&vec![2]).unwrap();
+        tdb.put_with_ttl(&vec![3, 3], &vec![], Duration::from_secs(60))
+            .unwrap();
+
+        // Live entries are 44 bytes long, plus 8 with an expiry, and the
+        // tombstone is 34 bytes long.
+        let stats = tdb.stats();
+        assert_eq!(stats.keys, 2);
+        assert_eq!(stats.files.len(), 1);
+        let file = &stats.files[0];
+        assert_eq!(file.size, 8 + 3 * 44 + 34 + 43);
+        assert_eq!(file.live_bytes, 44 + 43);
+        assert_eq!(file.dead_bytes, 2 * 44 + 34);
+        assert_eq!(file.tombstones, 1);
+        assert_eq!(stats.total_bytes, file.size);
+        assert_eq!(stats.key_sizes.counts, vec![0, 1, 1]);
+        assert_eq!(stats.value_sizes.counts, vec![1, 0, 0, 0, 1]);
+        assert_eq!(SizeHistogram::bucket_range(4), 8..=15);
+        assert!(stats.keydir_memory > 0);
+        drop(tdb);
+
+        // Replaying the hints gives the same counts.
+        let reopened = BitCask::open(&data_dir).unwrap().stats();
+        assert_eq!(reopened.files, stats.files);
+        assert_eq!(reopened.value_sizes, stats.value_sizes);
+
+        let mut tdb = BitCask::open_with_opts(&data_dir, Opts::new(true, SyncMode::Never)).unwrap();
+        tdb.merge().unwrap();
+        let stats = tdb.stats();
+        assert_eq!(stats.dead_bytes, 0);
+        assert_eq!(stats.live_bytes, 44 + 43);
+        assert!(stats.files.iter().all(|f| f.tombstones == 0));
+
+        // An expired value is dead right away, as it is after a restart, so
+        // its file reaches the merge thresholds.
+        tdb.put_with_ttl(&vec![4], &vec![4; 10], Duration::from_millis(1))
+            .unwrap();
+        thread::sleep(Duration::from_millis(5));
+        let stats = tdb.stats();
+        assert_eq!(stats.keys, 2);
+        assert_eq!(stats.live_bytes, 44 + 43);
+        assert_eq!(stats.dead_bytes, 52);
+        let expired_file_id = stats
+            .files
+            .iter()
+            .find(|f| f.dead_bytes > 0)
+            .unwrap()
+            .file_id;
+        drop(tdb);
+        let reopened = BitCask::open(&data_dir).unwrap().stats();
+        assert_eq!(reopened.keys, stats.keys);
+        assert_eq!(reopened.files, stats.files);
+        let mut opts = Opts::new(true, SyncMode::Never);
+        opts.merge_dead_ratio(Some(0.5));
+        let mut tdb = BitCask::open_with_opts(&data_dir, opts).unwrap();
+        tdb.merge().unwrap();
+        let files = tdb.stats().files;
+        assert!(files.iter().all(|f| f.file_id != expired_file_id));
+    }
+
+    #[test]
+    fn merge_files_test() {
+        let data_dir = generate_random_data_dir();
+        let open = |opts| BitCask::open_with_opts(&data_dir, opts).unwrap();
+        let mut tdb = open(Opts::new(true, SyncMode::Never));
+        for i in 1..=3 {
+            tdb.put(&vec![i], &vec![i; 10]).unwrap();
+        }
+        drop(tdb);
+        let mut tdb = open(Opts::new(true, SyncMode::Never));
+        tdb.delete(&vec![1]).unwrap();
+        tdb.put(&vec![2], &vec![5; 10]).unwrap();
+        drop(tdb);
+        let mut tdb = open(Opts::new(true, SyncMode::Never));
+        tdb.put(&vec![4], &vec![4; 10]).unwrap();
+        drop(tdb);
+
+        // The tombstone of the first key still shadows its value in file 0.
+        let mut tdb = open(Opts::new(true, SyncMode::Never));
+        tdb.merge_files(&[1]).unwrap();
+        let file_ids =
+            |tdb: &BitCask| -> Vec<usize> { tdb.stats().files.iter().map(|f| f.file_id).collect() };
+        assert_eq!(file_ids(&tdb), vec![0, 2, 5, 6]);
+        assert!(tdb.merge_files(&[1]).is_err());
+        drop(tdb);
+        let mut opts = Opts::new(true, SyncMode::Never);
+        opts.merge_dead_ratio(Some(0.5));
+        let mut tdb = open(opts);
+        assert_eq!(tdb.get(&vec![1]).unwrap(), None);
+        assert_eq!(tdb.get(&vec![2]).unwrap(), Some(vec![5; 10]));
+
+        // Two of the three entries in file 0 are dead, but only one of the
+        // two in the merge output.
+        tdb.merge().unwrap();
+        let ids = file_ids(&tdb);
+        assert!(!ids.contains(&0));
+        assert!(ids.contains(&5));
+        assert!(tdb.verify().unwrap().is_ok());
+        drop(tdb);
+        let tdb = BitCask::open(&data_dir).unwrap();
+        let pairs: Vec<_> = tdb.iter().map(|pair| pair.unwrap()).collect();
+        assert_eq!(
+            pairs,
+            vec![
+                (vec![2], vec![5; 10]),
+                (vec![3], vec![3; 10]),
+                (vec![4], vec![4; 10])
+            ]
+        );
+    }
+
+    #[test]
+    fn auto_merge_test() {
+        // Writes made while the live values are copied are caught up with.
+        let data_dir = generate_random_data_dir();
+        let open = || BitCask::open_with_opts(&data_dir, Opts::new(true, SyncMode::Never));
+        let mut tdb = open().unwrap();
+        for i in 0..3 {
+            tdb.put(&vec![i], &vec![i]).unwrap();
+        }
+        let file_ids = tdb.storage.read().unwrap().files_to_merge();
+        let mut merger = tdb.storage.write().unwrap().start_merge(file_ids).unwrap();
+        assert!(merger.write(|| false).unwrap());
+        tdb.put(&vec![0], &vec![5]).unwrap();
+        tdb.delete(&vec![1]).unwrap();
+        tdb.storage.write().unwrap().finish_merge(merger).unwrap();
+        let check = |tdb: &BitCask| {
+            assert_eq!(tdb.get(&vec![0]).unwrap(), Some(vec![5]));
+            assert_eq!(tdb.get(&vec![1]).unwrap(), None);
+            assert_eq!(tdb.get(&vec![2]).unwrap(), Some(vec![2]));
+        };
+        check(&tdb);
+        drop(tdb);
+        check(&open().unwrap());
+
+        // A batch that moves on to a new file while a merge runs is not
+        // split around the merge output.
+        let data_dir = generate_random_data_dir();
+        let open = || {
+            let mut opts = Opts::new(true, SyncMode::Never);
+            opts.max_file_size(100);
+            BitCask::open_with_opts(&data_dir, opts)
+        };
+        let mut tdb = open().unwrap();
+        for i in 0..3 {
+            tdb.put(&vec![i], &vec![i; 20]).unwrap();
+        }
+        let file_ids = tdb.storage.read().unwrap().files_to_merge();
+        let mut merger = tdb.storage.write().unwrap().start_merge(file_ids).unwrap();
+        assert!(merger.write(|| false).unwrap());
+        let mut batch = WriteBatch::new();
+        for i in 10..18 {
+            batch.put(&vec![i], &vec![i; 20]);
+        }
+        tdb.write_batch(&batch).unwrap();
+        tdb.storage.write().unwrap().finish_merge(merger).unwrap();
+        tdb.close().unwrap();
+        drop(tdb);
+        let tdb = open().unwrap();
+        for i in (0..3).chain(10..18) {
+            assert_eq!(tdb.get(&vec![i]).unwrap(), Some(vec![i; 20]));
+        }
+        drop(tdb);
+
+        let data_dir = generate_random_data_dir();
+        let mut opts = Opts::new(true, SyncMode::Never);
+        opts.max_file_size(1000);
+        opts.auto_merge_interval(Some(Duration::from_millis(5)));
+        opts.auto_merge_dead_bytes(Some(1));
+        let mut tdb = BitCask::open_with_opts(&data_dir, opts).unwrap();
+        let mut expected = BTreeMap::new();
+        // Writes go on while merges run.
+        for i in 0..500_u32 {
+            let key = vec![(i % 10) as u8];
+            tdb.put(&key, &i.to_be_bytes().to_vec()).unwrap();
+            expected.insert(key.clone(), i.to_be_bytes().to_vec());
+            if i % 7 == 0 {
+                tdb.delete(&key).unwrap();
+                expected.remove(&key);
+            }
+            if i % 50 == 0 {
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+        let mut waited = 0;
+        while tdb.stats().dead_bytes > 0 && waited < 5000 {
+            thread::sleep(Duration::from_millis(10));
+            waited += 10;
+        }
+        assert_eq!(tdb.stats().dead_bytes, 0);
+        let expected: Vec<_> = expected.into_iter().collect();
+        let pairs: Vec<_> = tdb.iter().map(|pair| pair.unwrap()).collect();
+        assert_eq!(pairs, expected);
+        tdb.close().unwrap();
+        drop(tdb);
+        let tdb = BitCask::open(&data_dir).unwrap();
+        let pairs: Vec<_> = tdb.iter().map(|pair| pair.unwrap()).collect();
+        assert_eq!(pairs, expected);
+        assert!(tdb.verify().unwrap().is_ok());
+        drop(tdb);
+
+        // A slow merge is given up on close.
+        let mut opts = Opts::new(true, SyncMode::Never);
+        opts.auto_merge_interval(Some(Duration::from_millis(5)));
+        opts.auto_merge_dead_bytes(Some(1));
+        opts.auto_merge_rate(Some(1000));
+        let mut tdb = BitCask::open_with_opts(&data_dir, opts).unwrap();
+        for i in 0..10 {
+            tdb.put(&vec![i], &vec![0; 1000]).unwrap();
+            tdb.put(&vec![i], &vec![1; 1000]).unwrap();
+        }
+        thread::sleep(Duration::from_millis(50));
+        assert!(matches!(tdb.merge(), Err(DBError::LockError(_))));
+        let start = std::time::Instant::now();
+        tdb.close().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+        drop(tdb);
+        assert_eq!(count_files(&data_dir, "merge"), 0);
+        let tdb = BitCask::open(&data_dir).unwrap();
+        assert_eq!(tdb.get(&vec![9]).unwrap(), Some(vec![1; 1000]));
+        assert!(tdb.stats().dead_bytes > 0);
+    }
+
+    fn count_files(data_dir: &str, extension: &str) -> usize {
+        fs::read_dir(data_dir)
+            .unwrap()
+            .filter(|path| path.as_ref().unwrap().path().extension() == Some(extension.as_ref()))
+            .count()
+    }
+
+    fn read_dir_contents(data_dir: &str) -> BTreeMap<PathBuf, Vec<u8>> {
+        fs::read_dir(data_dir)
+            .unwrap()
+            .map(|path| {
+                let path = path.unwrap().path();
+                let contents = fs::read(&path).unwrap();
+                (path, contents)
+            })
+            .collect()
+    }
+
+    fn append_garbage(path: &str) {
+        let mut file = fs::OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(&[0xff; 5]).unwrap();
+    }
+
     fn generate_random_bitcask_instance() -> BitCask {
-        let file_name = generate_random_name();
-        let data_dir = format!("./data/{}", file_name);
-        let opts = Opts::new(true, true);
+        let data_dir = generate_random_data_dir();
+        let opts = Opts::new(true, SyncMode::Always);
         BitCask::open_with_opts(data_dir, opts).unwrap()
     }
 
+    fn generate_random_data_dir() -> String {
+        let file_name = generate_random_name();
+        format!("./data/{}", file_name)
+    }
+
     fn generate_random_name() -> String {
         let rng = rand::thread_rng();
         let rand_string: String = rng
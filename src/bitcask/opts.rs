@@ -1,19 +1,95 @@
 //! Options to tdb.
 
+use std::time::Duration;
+
+/// What to do when opening a database finds a corrupt entry in a data file
+/// other than the newest one. A damaged end of the newest file is always
+/// truncated, since that is what an interrupted write leaves behind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CorruptionPolicy {
+    /// Refuse to open the database.
+    #[default]
+    Fail,
+    /// Ignore the damaged spans of the file, replaying the valid entries
+    /// after each of them, and leave it untouched.
+    Skip,
+    /// Move the damaged spans of the file into the `quarantine` directory
+    /// inside the data directory and rewrite the file without them.
+    Quarantine,
+}
+
+/// When written data is forced to disk with `fsync`. An `Ok(())` from a write
+/// means the data survives a power loss only as far as the mode promises.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncMode {
+    /// Never sync explicitly and leave write-back to the operating system.
+    /// `Bitcask::sync` still works.
+    #[default]
+    Never,
+    /// Sync the data file before every write returns.
+    Always,
+    /// Sync from a background thread every `interval`, and as soon as
+    /// `max_unsynced_bytes` have been written since the last sync.
+    Periodic {
+        interval: Duration,
+        max_unsynced_bytes: u64,
+    },
+    /// Sync when the database is closed or dropped.
+    OnClose,
+}
+
 /// Options give when opening a database by calling `Bitcask::open_with_opts`.
 pub struct Opts {
     /// whether writable or not
     read_write: bool,
-    /// whether to sync on put
-    sync_on_put: bool,
+    /// when to sync written data
+    sync_mode: SyncMode,
+    /// how to handle corruption in older data files
+    corruption_policy: CorruptionPolicy,
+    /// size in bytes after which a data file is sealed
+    max_file_size: u64,
+    /// age after which a data file is sealed, if any
+    max_file_age: Option<Duration>,
+    /// how long to wait for the lock on the data directory
+    lock_timeout: Option<Duration>,
+    /// whether a reader runs alongside a writer
+    follow: bool,
+    /// how often a following reader refreshes by itself, if at all
+    refresh_interval: Option<Duration>,
+    /// share of dead bytes from which a merge picks a data file, if any
+    merge_dead_ratio: Option<f64>,
+    /// dead bytes from which a merge picks a data file, if any
+    merge_dead_bytes: Option<u64>,
+    /// how often a writer checks whether to merge in the background, if at all
+    auto_merge_interval: Option<Duration>,
+    /// dead bytes in all data files from which a background merge runs, if any
+    auto_merge_dead_bytes: Option<u64>,
+    /// time of day during which a background merge runs once a day, if any
+    auto_merge_window: Option<(Duration, Duration)>,
+    /// bytes per second a background merge may read and write, if limited
+    auto_merge_rate: Option<u64>,
 }
 
 impl Opts {
+    pub const DEFAULT_MAX_FILE_SIZE: u64 = 1_000_000; // 1MB
+
     #[inline]
-    pub fn new(read_write: bool, sync_on_put: bool) -> Opts {
+    pub fn new(read_write: bool, sync_mode: SyncMode) -> Opts {
         Opts {
             read_write,
-            sync_on_put,
+            sync_mode,
+            corruption_policy: CorruptionPolicy::default(),
+            max_file_size: Self::DEFAULT_MAX_FILE_SIZE,
+            max_file_age: None,
+            lock_timeout: None,
+            follow: false,
+            refresh_interval: None,
+            merge_dead_ratio: None,
+            merge_dead_bytes: None,
+            auto_merge_interval: None,
+            auto_merge_dead_bytes: None,
+            auto_merge_window: None,
+            auto_merge_rate: None,
         }
     }
 
@@ -23,8 +99,107 @@ impl Opts {
     }
 
     #[inline]
-    pub fn sync_on_put(&mut self, sync_on_put: bool) {
-        self.sync_on_put = sync_on_put;
+    pub fn sync_mode(&mut self, sync_mode: SyncMode) {
+        self.sync_mode = sync_mode;
+    }
+
+    #[inline]
+    pub fn corruption_policy(&mut self, corruption_policy: CorruptionPolicy) {
+        self.corruption_policy = corruption_policy;
+    }
+
+    /// Seals the active data file and starts a new one before a write would
+    /// make it larger than `max_file_size` bytes. Merge output files are
+    /// split at the same size. A single entry larger than this gets a file of
+    /// its own.
+    #[inline]
+    pub fn max_file_size(&mut self, max_file_size: u64) {
+        self.max_file_size = max_file_size;
+    }
+
+    /// Also seals the active data file on the first write once it has been
+    /// written to for `max_file_age`, so that old data ends up in sealed files
+    /// even if it comes in slowly.
+    #[inline]
+    pub fn max_file_age(&mut self, max_file_age: Option<Duration>) {
+        self.max_file_age = max_file_age;
+    }
+
+    /// A writer locks the data directory for itself, and readers share a
+    /// lock that keeps writers out. When the lock is held, opening waits up
+    /// to `lock_timeout` for it and then fails with `DBError::LockError`.
+    /// Without a timeout it fails right away.
+    #[inline]
+    pub fn lock_timeout(&mut self, lock_timeout: Option<Duration>) {
+        self.lock_timeout = lock_timeout;
+    }
+
+    /// Lets a reader open the data directory while a writer has it, and the
+    /// writer open it while such readers have it. The reader takes no lock
+    /// and catches up with the writer on `Bitcask::refresh`. Ignored by
+    /// writers.
+    #[inline]
+    pub fn follow(&mut self, follow: bool) {
+        self.follow = follow;
+    }
+
+    /// Makes a following reader refresh by itself every `refresh_interval`
+    /// from a background thread.
+    #[inline]
+    pub fn refresh_interval(&mut self, refresh_interval: Option<Duration>) {
+        self.refresh_interval = refresh_interval;
+    }
+
+    /// Makes `Bitcask::merge` only rewrite the data files in which at least
+    /// `merge_dead_ratio` of the entry bytes are dead, i.e. taken up by
+    /// overwritten, deleted or expired values and by tombstones. Without this
+    /// or [`merge_dead_bytes`](Self::merge_dead_bytes), every file is merged.
+    #[inline]
+    pub fn merge_dead_ratio(&mut self, merge_dead_ratio: Option<f64>) {
+        self.merge_dead_ratio = merge_dead_ratio;
+    }
+
+    /// Makes `Bitcask::merge` also rewrite the data files with at least
+    /// `merge_dead_bytes` dead bytes.
+    #[inline]
+    pub fn merge_dead_bytes(&mut self, merge_dead_bytes: Option<u64>) {
+        self.merge_dead_bytes = merge_dead_bytes;
+    }
+
+    /// Makes a writer merge from a background thread, which checks every
+    /// `auto_merge_interval` whether a merge is due. One is due when a data
+    /// file reaches [`merge_dead_ratio`](Self::merge_dead_ratio) or
+    /// [`merge_dead_bytes`](Self::merge_dead_bytes), which picks the files
+    /// to merge, or else when
+    /// [`auto_merge_dead_bytes`](Self::auto_merge_dead_bytes) or
+    /// [`auto_merge_window`](Self::auto_merge_window) says so, which merges
+    /// every file with dead bytes. Reads and writes go on during the merge,
+    /// except for short moments at its start and end.
+    #[inline]
+    pub fn auto_merge_interval(&mut self, auto_merge_interval: Option<Duration>) {
+        self.auto_merge_interval = auto_merge_interval;
+    }
+
+    /// Makes a background merge due once the data files hold at least
+    /// `auto_merge_dead_bytes` dead bytes in all.
+    #[inline]
+    pub fn auto_merge_dead_bytes(&mut self, auto_merge_dead_bytes: Option<u64>) {
+        self.auto_merge_dead_bytes = auto_merge_dead_bytes;
+    }
+
+    /// Makes a background merge due once a day between the two times of
+    /// day, given as the time since midnight UTC. The window wraps around
+    /// midnight if it ends before it starts.
+    #[inline]
+    pub fn auto_merge_window(&mut self, auto_merge_window: Option<(Duration, Duration)>) {
+        self.auto_merge_window = auto_merge_window;
+    }
+
+    /// Keeps background merges from reading and writing more than
+    /// `auto_merge_rate` bytes per second on average.
+    #[inline]
+    pub fn auto_merge_rate(&mut self, auto_merge_rate: Option<u64>) {
+        self.auto_merge_rate = auto_merge_rate;
     }
 
     #[inline]
@@ -33,7 +208,67 @@ impl Opts {
     }
 
     #[inline]
-    pub(crate) fn do_sync_on_put(&self) -> bool {
-        self.sync_on_put
+    pub(crate) fn get_sync_mode(&self) -> SyncMode {
+        self.sync_mode
+    }
+
+    #[inline]
+    pub(crate) fn get_corruption_policy(&self) -> CorruptionPolicy {
+        self.corruption_policy
+    }
+
+    #[inline]
+    pub(crate) fn get_max_file_size(&self) -> u64 {
+        self.max_file_size
+    }
+
+    #[inline]
+    pub(crate) fn get_max_file_age(&self) -> Option<Duration> {
+        self.max_file_age
+    }
+
+    #[inline]
+    pub(crate) fn get_lock_timeout(&self) -> Option<Duration> {
+        self.lock_timeout
+    }
+
+    #[inline]
+    pub(crate) fn is_following(&self) -> bool {
+        !self.read_write && self.follow
+    }
+
+    #[inline]
+    pub(crate) fn get_refresh_interval(&self) -> Option<Duration> {
+        self.refresh_interval
+    }
+
+    #[inline]
+    pub(crate) fn get_merge_dead_ratio(&self) -> Option<f64> {
+        self.merge_dead_ratio
+    }
+
+    #[inline]
+    pub(crate) fn get_merge_dead_bytes(&self) -> Option<u64> {
+        self.merge_dead_bytes
+    }
+
+    #[inline]
+    pub(crate) fn get_auto_merge_interval(&self) -> Option<Duration> {
+        self.auto_merge_interval
+    }
+
+    #[inline]
+    pub(crate) fn get_auto_merge_dead_bytes(&self) -> Option<u64> {
+        self.auto_merge_dead_bytes
+    }
+
+    #[inline]
+    pub(crate) fn get_auto_merge_window(&self) -> Option<(Duration, Duration)> {
+        self.auto_merge_window
+    }
+
+    #[inline]
+    pub(crate) fn get_auto_merge_rate(&self) -> Option<u64> {
+        self.auto_merge_rate
     }
 }
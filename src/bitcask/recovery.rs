@@ -0,0 +1,62 @@
+//! What happened to damaged data files while opening or repairing a database.
+
+use std::path::PathBuf;
+
+/// Describes the repairs made by `Bitcask::open_with_opts`. Retrieve it with
+/// `Bitcask::recovery_report`.
+#[derive(Debug, Clone, Default)]
+pub struct RecoveryReport {
+    /// Number of bytes cut off the end of the newest data file because its
+    /// last entry was incomplete or corrupt, typically after a crash in the
+    /// middle of a write. In read-only access they are only ignored.
+    pub truncated_bytes: u64,
+    /// Damaged spans of older data files that were skipped or quarantined
+    /// according to the configured [`CorruptionPolicy`](crate::CorruptionPolicy).
+    pub corrupted_files: Vec<CorruptedFile>,
+}
+
+/// A span of a data file that held no valid entry. Reading went on with
+/// the next valid entry after it.
+#[derive(Debug, Clone)]
+pub struct CorruptedFile {
+    pub file_id: usize,
+    /// Offset of the span in the file as it was before recovery.
+    pub offset: u64,
+    /// Length of the span.
+    pub ignored_bytes: u64,
+    /// Where the ignored bytes were moved to, if they were quarantined. Never
+    /// set in read-only access, where quarantining falls back to skipping.
+    pub quarantine_path: Option<PathBuf>,
+}
+
+/// Describes what `Bitcask::repair` did to the data files.
+#[derive(Debug, Clone, Default)]
+pub struct RepairReport {
+    /// Number of data files checked.
+    pub files: usize,
+    /// Number of valid entries found, all of which were kept.
+    pub entries: u64,
+    /// Damaged spans moved out of the data files, in log order.
+    pub quarantined: Vec<QuarantinedSpan>,
+    /// Keys whose latest version may have been in a damaged span, in order.
+    /// That is every key whose latest entry left comes before a damaged span
+    /// long enough to have held an entry for it, and the key of a damaged
+    /// entry whose header could still be read. This over-approximates, since
+    /// a span need not have held anything for the keys before it, while keys
+    /// only found in spans too damaged to read cannot be named.
+    pub keys_at_risk: Vec<Vec<u8>>,
+    /// Where this report was written in the `quarantine` directory, if
+    /// anything was damaged.
+    pub report_path: Option<PathBuf>,
+}
+
+/// A span of a data file that held no valid entry.
+#[derive(Debug, Clone)]
+pub struct QuarantinedSpan {
+    pub file_id: usize,
+    /// Offset of the span in the file as it was before the repair.
+    pub offset: u64,
+    pub len: u64,
+    /// Where the bytes of the span were moved to.
+    pub path: PathBuf,
+}
@@ -0,0 +1,84 @@
+//! Background thread behind [`Opts::refresh_interval`](super::opts::Opts).
+
+use std::{
+    sync::{Arc, Condvar, Mutex, RwLock, Weak},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crate::error::DBError;
+
+use super::storage::Storage;
+
+#[derive(Default)]
+struct State {
+    /// Set to make the thread exit.
+    stop: bool,
+    /// The last failed refresh, reported by the next call to
+    /// [`Refresher::check`].
+    error: Option<DBError>,
+}
+
+/// Refreshes a following reader every `interval`.
+pub(super) struct Refresher {
+    shared: Arc<(Mutex<State>, Condvar)>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Refresher {
+    pub(super) fn spawn(storage: Weak<RwLock<Storage>>, interval: Duration) -> Self {
+        let shared = Arc::new((Mutex::new(State::default()), Condvar::new()));
+        let thread_shared = shared.clone();
+        let handle = thread::spawn(move || {
+            let (state, condvar) = &*thread_shared;
+            loop {
+                let guard = state.lock().unwrap();
+                let (guard, _) = condvar
+                    .wait_timeout_while(guard, interval, |s| !s.stop)
+                    .unwrap();
+                if guard.stop {
+                    break;
+                }
+                drop(guard);
+
+                let Some(storage) = storage.upgrade() else {
+                    break;
+                };
+                let res = storage.write().unwrap().refresh();
+                if let Err(e) = res {
+                    state.lock().unwrap().error = Some(e);
+                }
+            }
+        });
+
+        Self {
+            shared,
+            handle: Some(handle),
+        }
+    }
+
+    /// Returns the error of a background refresh that failed since the last
+    /// call.
+    pub(super) fn check(&self) -> Result<(), DBError> {
+        match self.shared.0.lock().unwrap().error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Stops the thread and waits for it to exit.
+    pub(super) fn stop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            let (state, condvar) = &*self.shared;
+            state.lock().unwrap().stop = true;
+            condvar.notify_one();
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for Refresher {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
@@ -0,0 +1,74 @@
+//! Read-only views of a database at one point in time.
+
+use std::ops::RangeBounds;
+
+use crate::error::DBError;
+
+use super::{
+    iter::{self, Iter, Keys, Values},
+    keydir::{KeyDir, KeyDirEntry},
+    log::FileSet,
+    Key, Value,
+};
+
+/// The database as it was when [`BitCask::snapshot`](super::BitCask::snapshot)
+/// was called. Writes made afterwards are not seen, though keys still expire
+/// as time goes by. The data files it reads from are kept until it is
+/// dropped, even if a merge replaces them. Clones are cheap and share the
+/// same view.
+#[derive(Clone)]
+pub struct Snapshot {
+    keydir: KeyDir,
+    files: FileSet,
+}
+
+impl Snapshot {
+    pub(super) fn new(keydir: KeyDir, files: FileSet) -> Self {
+        Self { keydir, files }
+    }
+
+    pub fn get(&self, key: &Key) -> Result<Option<Value>, DBError> {
+        match self.keydir.get(key).filter(|entry| !entry.is_expired()) {
+            Some(entry) => self.files.read_value(entry).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns every key/value pair, in key order.
+    pub fn iter(&self) -> Iter {
+        self.scan(..)
+    }
+
+    /// Returns every key, in order, without reading any value.
+    pub fn keys(&self) -> Keys {
+        Keys::new(self.clone())
+    }
+
+    /// Returns every value, in key order.
+    pub fn values(&self) -> Values {
+        Values::new(self.iter())
+    }
+
+    /// Returns the pairs with keys in `range`, in key order.
+    pub fn scan<R: RangeBounds<Key>>(&self, range: R) -> Iter {
+        let lower = range.start_bound().cloned();
+        let upper = range.end_bound().cloned();
+        Iter::new(self.clone(), lower, upper)
+    }
+
+    /// Returns the pairs with keys starting with `prefix`, in key order.
+    pub fn scan_prefix(&self, prefix: &Key) -> Iter {
+        let (lower, upper) = iter::prefix_bounds(prefix);
+        Iter::new(self.clone(), lower, upper)
+    }
+
+    #[inline]
+    pub(super) fn get_keydir(&self) -> &KeyDir {
+        &self.keydir
+    }
+
+    #[inline]
+    pub(super) fn read_value(&self, keydir_entry: &KeyDirEntry) -> Result<Value, DBError> {
+        self.files.read_value(keydir_entry)
+    }
+}
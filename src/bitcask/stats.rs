@@ -0,0 +1,68 @@
+//! How much space a database takes up, returned by `Bitcask::stats`.
+
+use std::ops::RangeInclusive;
+
+/// Statistics over the whole database. They are kept up to date as writes
+/// happen, so getting them reads nothing from disk.
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    /// Number of keys whose value has not expired.
+    pub keys: u64,
+    /// Total size of the data files.
+    pub total_bytes: u64,
+    /// Size of the entries holding the current value of a key, unless it has
+    /// expired.
+    pub live_bytes: u64,
+    /// Size of the entries that were overwritten, deleted or have expired,
+    /// and of the tombstones, which a merge reclaims.
+    pub dead_bytes: u64,
+    /// Every data file, in order.
+    pub files: Vec<FileStats>,
+    /// Estimated memory used by the keydir, in bytes.
+    pub keydir_memory: u64,
+    pub key_sizes: SizeHistogram,
+    pub value_sizes: SizeHistogram,
+}
+
+/// Statistics over one data file. Besides its live and dead bytes, it has a
+/// header of a few bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileStats {
+    pub file_id: usize,
+    pub size: u64,
+    pub live_bytes: u64,
+    pub dead_bytes: u64,
+    /// Number of deletions recorded in the file.
+    pub tombstones: u64,
+}
+
+/// Number of keys or values by size, in power-of-two buckets.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SizeHistogram {
+    /// Count of every bucket, up to the last one that is not empty. See
+    /// [`SizeHistogram::bucket_range`] for the sizes each one covers.
+    pub counts: Vec<u64>,
+}
+
+impl SizeHistogram {
+    /// Returns the sizes counted in bucket `i`: 0 for the first one, then
+    /// 1, 2 to 3, 4 to 7, and so on.
+    pub fn bucket_range(i: usize) -> RangeInclusive<u64> {
+        match i {
+            0 => 0..=0,
+            i => 1 << (i - 1)..=(u64::MAX >> (u64::BITS as usize - i)),
+        }
+    }
+
+    /// Returns the bucket counting `size`.
+    pub(super) fn bucket(size: u64) -> usize {
+        (u64::BITS - size.leading_zeros()) as usize
+    }
+
+    pub(super) fn new(mut counts: Vec<u64>) -> Self {
+        while counts.last() == Some(&0) {
+            counts.pop();
+        }
+        Self { counts }
+    }
+}
@@ -1,29 +1,68 @@
-use std::{fs, path::PathBuf};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    io::Read,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use crate::error::DBError;
 
-use super::{keydir::KeyDir, log::Log, Key, Value};
+use super::{
+    batch::{BatchOp, WriteBatch},
+    export::{self, ExportFormat},
+    keydir::KeyDir,
+    lock::DirLock,
+    log::{Checkpoint, Log, Merger, Verifier},
+    now_micros,
+    opts::Opts,
+    recovery::{RecoveryReport, RepairReport},
+    snapshot::Snapshot,
+    stats::{SizeHistogram, Stats},
+    FileId, Key, Value,
+};
 
 pub(super) struct Storage {
     log: Log,
     keydir: KeyDir,
+    /// Held until the database is closed. Readers go without one if there is
+    /// no lock file, and following readers always do.
+    lock: Option<DirLock>,
 }
 
 impl Storage {
-    pub(super) fn new<T: Into<PathBuf>>(data_dir: T) -> Result<Self, DBError> {
+    /// How many bytes of keys and values an import gathers before appending.
+    const IMPORT_CHUNK_SIZE: usize = 1 << 20;
+
+    pub(super) fn new<T: Into<PathBuf>>(data_dir: T, opts: &Opts) -> Result<Self, DBError> {
         let data_dir = data_dir.into();
-        fs::create_dir_all(&data_dir)?;
+        // Read-only access must not write anything, not even the directory.
+        if opts.is_mutable() {
+            fs::create_dir_all(&data_dir)?;
+        }
+        let lock = if opts.is_following() {
+            None
+        } else {
+            DirLock::acquire(&data_dir, opts.is_mutable(), opts.get_lock_timeout())?
+        };
         let mut keydir = KeyDir::new();
-        let log = Log::from_disk(&data_dir, &mut keydir)?;
+        let log = Log::from_disk(&data_dir, &mut keydir, opts)?;
+
+        Ok(Self { log, keydir, lock })
+    }
 
-        Ok(Self { log, keydir })
+    /// Repairs the data files in `data_dir` while holding the lock of a
+    /// writer.
+    pub(super) fn repair(data_dir: &Path) -> Result<RepairReport, DBError> {
+        let _lock = DirLock::acquire(data_dir, true, None)?;
+        Log::repair(data_dir)
     }
 
     pub(super) fn get(&self, key: &Key) -> Result<Option<Value>, DBError> {
-        let keydir_entry = self.keydir.get(key);
+        let keydir_entry = self.keydir.get(key).filter(|entry| !entry.is_expired());
         match keydir_entry {
             Some(entry) => {
-                let value = self.log.get(&entry)?;
+                let value = self.log.get(entry)?;
                 Ok(Some(value))
             }
             None => Ok(None),
@@ -34,55 +73,231 @@ impl Storage {
         &mut self,
         key: &Key,
         value: &Value,
-        sync_on_put: bool,
+        ttl: Option<Duration>,
     ) -> Result<(), DBError> {
-        let keydir_entry = self.log.put(key, value, sync_on_put)?;
+        let keydir_entry = self.log.put(key, value, ttl)?;
         self.keydir.put(key.clone(), keydir_entry);
 
         Ok(())
     }
 
-    pub(super) fn delete(&mut self, key: &Key, sync_on_put: bool) -> Result<(), DBError> {
-        self.log.delete(key, sync_on_put)?;
-        self.keydir.delete(key);
+    pub(super) fn delete(&mut self, key: &Key) -> Result<(), DBError> {
+        let keydir_entry = self.log.delete(key)?;
+        self.keydir.delete_at(key, keydir_entry.timestamp);
 
         Ok(())
     }
 
+    pub(super) fn write_batch(&mut self, batch: &WriteBatch) -> Result<(), DBError> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let keydir_entries = self.log.write_batch(batch.ops(), true, true)?;
+        for (op, keydir_entry) in batch.ops().iter().zip(keydir_entries) {
+            match op {
+                BatchOp::Put(key, ..) => {
+                    self.keydir.put(key.clone(), keydir_entry);
+                }
+                BatchOp::Delete(key) => {
+                    self.keydir.delete_at(key, keydir_entry.timestamp);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Applies the writes of a transaction unless one of the keys it read,
+    /// given with the timestamp of the latest write to them in its snapshot,
+    /// has been written since. Every write, deletions included, gets a new
+    /// timestamp, so comparing timestamps is enough to notice. A key that
+    /// had none may have been written and deleted in a deletion epoch since
+    /// `deletions_epoch`, so it is taken as written.
+    pub(super) fn commit(
+        &mut self,
+        reads: &BTreeMap<Key, Option<u64>>,
+        deletions_epoch: u64,
+        batch: &WriteBatch,
+    ) -> Result<(), DBError> {
+        let forgotten = self.keydir.get_deletions_epoch() != deletions_epoch;
+        for (key, then) in reads {
+            let now = self.keydir.last_write(key);
+            if *then != now || (then.is_none() && forgotten) {
+                return Err(DBError::ConflictError(format!(
+                    "key {:?} was written by another writer",
+                    key
+                )));
+            }
+        }
+        self.write_batch(batch)
+    }
+
+    /// Puts every pair of an export stream, appending them in chunks as one
+    /// batch that is only completed, and made visible, once the trailer of
+    /// the stream has been checked. Pairs that have already expired are
+    /// skipped. Returns the number of pairs in the stream.
+    pub(super) fn import<R: Read>(
+        &mut self,
+        reader: R,
+        format: ExportFormat,
+    ) -> Result<u64, DBError> {
+        let mut ops = vec![];
+        let mut ops_sz = 0;
+        let mut first = true;
+        let mut imported = vec![];
+        let count = export::import(reader, format, |key, value, expiry| {
+            if expiry.is_some_and(|expiry| expiry <= now_micros()) {
+                return Ok(());
+            }
+            ops_sz += key.len() + value.len();
+            ops.push(BatchOp::Put(key, value, expiry));
+            // The last pair is held back, so that there is one left to end
+            // the batch with.
+            if ops_sz >= Self::IMPORT_CHUNK_SIZE && ops.len() > 1 {
+                let held_back = ops.pop().unwrap();
+                let keydir_entries = self.log.write_batch(&ops, first, false)?;
+                imported.extend(ops.drain(..).zip(keydir_entries));
+                ops_sz = 0;
+                ops.push(held_back);
+                first = false;
+            }
+            Ok(())
+        })?;
+        if !ops.is_empty() {
+            let keydir_entries = self.log.write_batch(&ops, first, true)?;
+            imported.extend(ops.drain(..).zip(keydir_entries));
+        }
+        for (op, keydir_entry) in imported {
+            if let BatchOp::Put(key, ..) = op {
+                self.keydir.put(key, keydir_entry);
+            }
+        }
+
+        Ok(count)
+    }
+
+    pub(super) fn get_recovery_report(&self) -> &RecoveryReport {
+        self.log.get_recovery_report()
+    }
+
     pub(super) fn list_keys(&self) -> Vec<Key> {
-        self.keydir.list_keys()
+        self.keydir
+            .iter()
+            .filter(|(_, entry)| !entry.is_expired())
+            .map(|(key, _)| key.clone())
+            .collect()
     }
 
-    pub(super) fn fold<F, Acc>(&self, fun: F, acc0: Acc) -> Result<Acc, DBError>
-    where
-        F: Fn(Key, Value, Acc) -> Acc,
-    {
-        let mut acc = acc0;
-        for k in self.keydir.list_keys() {
-            let value = self.get(&k)?.unwrap();
-            acc = fun(k, value, acc);
+    pub(super) fn stats(&self) -> Stats {
+        let keydir_stats = self.keydir.get_stats();
+        let files = self.log.file_stats(&keydir_stats);
+        Stats {
+            keys: keydir_stats.keys,
+            total_bytes: files.iter().map(|f| f.size).sum(),
+            live_bytes: files.iter().map(|f| f.live_bytes).sum(),
+            dead_bytes: files.iter().map(|f| f.dead_bytes).sum(),
+            files,
+            keydir_memory: self.keydir.estimate_memory(),
+            key_sizes: SizeHistogram::new(keydir_stats.key_sizes),
+            value_sizes: SizeHistogram::new(keydir_stats.value_sizes),
         }
+    }
 
-        Ok(acc)
+    /// Returns a read-only view of the database as it is now.
+    pub(super) fn snapshot(&self) -> Snapshot {
+        Snapshot::new(self.keydir.clone(), self.log.file_set())
     }
 
-    pub(super) fn merge(&mut self, sync_on_put: bool) -> Result<(), DBError> {
-        for k in self.keydir.list_keys() {
-            let value = self.get(&k)?.unwrap();
-            self.put_on_merge(k, value, sync_on_put)?;
+    /// Merges the data files picked by the merge thresholds, or every file if
+    /// there are none.
+    pub(super) fn merge(&mut self) -> Result<(), DBError> {
+        let file_ids = self.files_to_merge();
+        if file_ids.is_empty() {
+            return Ok(());
         }
-        self.log.finish_merge()?;
+        self.merge_files(file_ids)
+    }
 
-        Ok(())
+    /// Returns the data files over one of the merge thresholds, or every
+    /// file if there are none.
+    pub(super) fn files_to_merge(&self) -> BTreeSet<FileId> {
+        self.log.files_to_merge(&self.keydir.get_stats())
+    }
+
+    /// Rewrites the live keys of the data files `file_ids` into new data
+    /// files and replaces them with those. Either all of the merge takes
+    /// effect or none of it does, even across a crash.
+    pub(super) fn merge_files(&mut self, file_ids: BTreeSet<FileId>) -> Result<(), DBError> {
+        let mut merger = self.start_merge(file_ids)?;
+        match merger.write(|| false) {
+            Ok(_) => self.finish_merge(merger),
+            Err(e) => {
+                self.abort_merge(merger)?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Starts merging the data files `file_ids`. The returned merger copies
+    /// their live keys without holding up this storage, and is then passed
+    /// to [`Storage::finish_merge`] or [`Storage::abort_merge`].
+    pub(super) fn start_merge(&mut self, file_ids: BTreeSet<FileId>) -> Result<Merger, DBError> {
+        self.log.start_merge(file_ids, self.keydir.clone())
+    }
+
+    /// Catches the merge output up with the writes made since the merge
+    /// started, then commits it and switches to it.
+    pub(super) fn finish_merge(&mut self, mut merger: Merger) -> Result<(), DBError> {
+        let updates = merger
+            .reconcile(&self.keydir, |entry| self.log.get(entry))
+            .and_then(|updates| self.log.commit_merge(&mut merger).map(|_| updates));
+        let updates = match updates {
+            Ok(updates) => updates,
+            Err(e) => {
+                self.abort_merge(merger)?;
+                return Err(e);
+            }
+        };
+        for (key, keydir_entry) in updates {
+            match keydir_entry {
+                Some(keydir_entry) => self.keydir.put(key, keydir_entry),
+                None => self.keydir.delete(&key),
+            };
+        }
+        // The merge may have dropped the tombstones, so the deletions are
+        // not kept in memory any longer either.
+        self.keydir.forget_deletions();
+        self.log.install_merge(merger)
+    }
+
+    pub(super) fn abort_merge(&mut self, merger: Merger) -> Result<(), DBError> {
+        self.log.abort_merge(merger)
     }
 
-    pub(super) fn sync(&mut self) -> Result<(), DBError> {
+    /// Picks up what the writer of the data directory has written since the
+    /// last refresh. Only for readers.
+    pub(super) fn refresh(&mut self) -> Result<(), DBError> {
+        self.log.refresh(&mut self.keydir)
+    }
+
+    pub(super) fn checkpoint(&mut self) -> Result<Checkpoint, DBError> {
+        self.log.checkpoint()
+    }
+
+    pub(super) fn verifier(&self) -> Verifier {
+        self.log.verifier(self.keydir.clone())
+    }
+
+    pub(super) fn sync(&self) -> Result<(), DBError> {
         self.log.sync()
     }
 
-    fn put_on_merge(&mut self, key: Key, value: Value, sync_on_put: bool) -> Result<(), DBError> {
-        let keydir_entry = self.log.put_on_merge(&key, value, sync_on_put)?;
-        self.keydir.put(key, keydir_entry);
-        Ok(())
+    /// Lets other instances open the data directory.
+    pub(super) fn unlock(&mut self) {
+        self.lock = None;
+    }
+
+    pub(super) fn get_unsynced_bytes(&self) -> u64 {
+        self.log.get_unsynced_bytes()
     }
 }
@@ -0,0 +1,65 @@
+//! Optimistic read-modify-write transactions.
+
+use std::collections::BTreeMap;
+
+use crate::error::DBError;
+
+use super::{batch::WriteBatch, snapshot::Snapshot, Key, Value};
+
+/// Reads from a snapshot taken by
+/// [`BitCask::transaction`](super::BitCask::transaction) and buffers writes
+/// until [`BitCask::commit`](super::BitCask::commit). The commit fails with
+/// [`DBError::ConflictError`] if another write changed a key the transaction
+/// read, in which case the transaction can simply be run again.
+pub struct Transaction {
+    snapshot: Snapshot,
+    /// Keys read from the snapshot, with the timestamp of the latest write
+    /// to them there, be it a value or a deletion, `None` if there was none.
+    reads: BTreeMap<Key, Option<u64>>,
+    /// Values to write on commit, `None` for a delete.
+    writes: BTreeMap<Key, Option<Value>>,
+}
+
+impl Transaction {
+    pub(super) fn new(snapshot: Snapshot) -> Self {
+        Self {
+            snapshot,
+            reads: BTreeMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the value written by this transaction if there is one, and the
+    /// value at the start of the transaction otherwise.
+    pub fn get(&mut self, key: &Key) -> Result<Option<Value>, DBError> {
+        if let Some(value) = self.writes.get(key) {
+            return Ok(value.clone());
+        }
+        let timestamp = self.snapshot.get_keydir().last_write(key);
+        self.reads.entry(key.clone()).or_insert(timestamp);
+        self.snapshot.get(key)
+    }
+
+    pub fn put(&mut self, key: &Key, value: &Value) {
+        self.writes.insert(key.clone(), Some(value.clone()));
+    }
+
+    pub fn delete(&mut self, key: &Key) {
+        self.writes.insert(key.clone(), None);
+    }
+
+    /// Lets go of the snapshot and returns the keys read, with their
+    /// timestamps then, the epoch of the deletions the snapshot knew of, and
+    /// the writes to commit.
+    pub(super) fn into_commit(self) -> (BTreeMap<Key, Option<u64>>, u64, WriteBatch) {
+        let mut batch = WriteBatch::new();
+        for (key, value) in &self.writes {
+            match value {
+                Some(value) => batch.put(key, value),
+                None => batch.delete(key),
+            }
+        }
+        let deletions_epoch = self.snapshot.get_keydir().get_deletions_epoch();
+        (self.reads, deletions_epoch, batch)
+    }
+}
@@ -0,0 +1,48 @@
+//! What `Bitcask::verify` found wrong with the data files.
+
+/// Describes the problems found by `Bitcask::verify`. The database is
+/// healthy if there are none.
+#[derive(Debug, Clone, Default)]
+pub struct VerifyReport {
+    /// Number of data files checked.
+    pub files: usize,
+    /// Number of valid entries found in them.
+    pub entries: u64,
+    /// Entries that could not be read or failed their checksum.
+    pub bad_entries: Vec<BadEntry>,
+    /// Keys whose keydir entry does not point at a valid value.
+    pub bad_keys: Vec<BadKey>,
+}
+
+impl VerifyReport {
+    /// Whether no problem was found.
+    pub fn is_ok(&self) -> bool {
+        self.bad_entries.is_empty() && self.bad_keys.is_empty()
+    }
+}
+
+/// A damaged entry of a data file.
+#[derive(Debug, Clone)]
+pub struct BadEntry {
+    pub file_id: usize,
+    /// Offset of the entry in the file.
+    pub offset: u64,
+    /// Number of bytes affected. If the entry could not be framed, this runs
+    /// to the end of the file, since the entries after it cannot be found.
+    pub len: u64,
+    /// Key of the entry, if it could be read.
+    pub key: Option<Vec<u8>>,
+    /// What is wrong with it.
+    pub reason: String,
+}
+
+/// A key whose value cannot be read back.
+#[derive(Debug, Clone)]
+pub struct BadKey {
+    pub key: Vec<u8>,
+    pub file_id: usize,
+    /// Offset of the value the keydir points at.
+    pub value_pos: u64,
+    /// What is wrong with it.
+    pub reason: String,
+}
@@ -2,6 +2,7 @@
 
 use thiserror::Error;
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, Error)]
 pub enum DBError {
     #[error("Data is corrupted: {0}")]
@@ -10,4 +11,10 @@ pub enum DBError {
     IOError(#[from] std::io::Error),
     #[error("Bitcask is immutable: {0}")]
     OptionError(String),
+    #[error("Unsupported format version: {0}")]
+    VersionError(String),
+    #[error("Transaction conflict: {0}")]
+    ConflictError(String),
+    #[error("Database is locked: {0}")]
+    LockError(String),
 }
@@ -1,4 +1,19 @@
 mod bitcask;
 mod error;
 
-pub use crate::bitcask::{opts::Opts, BitCask as TDB};
+pub use crate::{
+    bitcask::{
+        backup::{BackupInfo, BackupRepository},
+        batch::WriteBatch,
+        export::ExportFormat,
+        iter::{Iter, Keys, Values},
+        opts::{CorruptionPolicy, Opts, SyncMode},
+        recovery::{CorruptedFile, QuarantinedSpan, RecoveryReport, RepairReport},
+        snapshot::Snapshot,
+        stats::{FileStats, SizeHistogram, Stats},
+        transaction::Transaction,
+        verify::{BadEntry, BadKey, VerifyReport},
+        BitCask as TDB,
+    },
+    error::DBError,
+};
@@ -0,0 +1,95 @@
+use std::{
+    fs,
+    io::Write,
+    process::{Command, Output},
+};
+
+fn tdb(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_tdb"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    assert!(output.status.success(), "{:?}", output);
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+#[test]
+fn cli_test() {
+    let data_dir = "data/cli";
+    let _ = fs::remove_dir_all("data/cli");
+    let _ = fs::remove_dir_all("data/cli_import");
+
+    assert!(tdb(&[data_dir, "put", "a", "1"]).status.success());
+    assert!(tdb(&[data_dir, "put", "ab", "2"]).status.success());
+    assert!(tdb(&["--hex", data_dir, "put", "00ff", "beef"])
+        .status
+        .success());
+    assert_eq!(stdout(&tdb(&[data_dir, "get", "a"])), "1\n");
+    assert_eq!(stdout(&tdb(&["--hex", data_dir, "get", "00ff"])), "beef\n");
+    assert_eq!(tdb(&[data_dir, "get", "b"]).status.code(), Some(1));
+    assert_eq!(
+        stdout(&tdb(&["--json", data_dir, "scan", "--prefix", "a"])),
+        "{\"key\":\"YQ==\",\"value\":\"MQ==\"}\n{\"key\":\"YWI=\",\"value\":\"Mg==\"}\n"
+    );
+    // Text escapes what is not UTF-8, and backslashes.
+    assert!(tdb(&["--hex", data_dir, "put", "78ff", "5c"])
+        .status
+        .success());
+    assert_eq!(
+        stdout(&tdb(&[data_dir, "scan", "--prefix", "x"])),
+        "x\\xff\t\\\\\n"
+    );
+    assert!(tdb(&["--hex", data_dir, "delete", "78ff"]).status.success());
+    assert_eq!(
+        stdout(&tdb(&["--hex", data_dir, "keys"])),
+        "00ff\n61\n6162\n"
+    );
+
+    fs::write("data/cli_key", "ab").unwrap();
+    assert!(tdb(&[data_dir, "delete", "--key-file", "data/cli_key"])
+        .status
+        .success());
+    assert!(tdb(&[data_dir, "merge"]).status.success());
+    assert!(!tdb(&[data_dir, "merge", "0"]).status.success());
+    assert!(stdout(&tdb(&[data_dir, "stats"])).starts_with("keys: 2\n"));
+
+    let export = "data/cli_export";
+    assert!(tdb(&[data_dir, "export", "--format", "jsonl", export])
+        .status
+        .success());
+    assert!(
+        tdb(&["data/cli_import", "import", "--format", "jsonl", export])
+            .status
+            .success()
+    );
+    assert_eq!(
+        stdout(&tdb(&["--hex", "data/cli_import", "keys"])),
+        "00ff\n61\n"
+    );
+
+    assert!(stdout(&tdb(&[data_dir, "verify"])).ends_with(": 0 bad entries, 0 bad keys\n"));
+    // Damage the end of an older file, since that of the newest one may be a
+    // write in progress.
+    assert!(tdb(&["data/cli_import", "put", "c", "3"]).status.success());
+    let mut data_file = fs::OpenOptions::new()
+        .append(true)
+        .open("data/cli_import/0.tdb")
+        .unwrap();
+    data_file.write_all(&[0xff; 5]).unwrap();
+    let output = tdb(&["--json", "data/cli_import", "verify"]);
+    assert_eq!(output.status.code(), Some(1));
+    let report = String::from_utf8(output.stdout).unwrap();
+    assert!(report.starts_with("{\"bad_entries\":[{\"file_id\":0,\"key\":null,\"len\":5,"));
+
+    let output = tdb(&["--json", "data/cli_import", "repair"]);
+    assert!(stdout(&output).contains("\"quarantined\":[{\"file_id\":0,\"len\":5,"));
+    assert!(tdb(&["data/cli_import", "verify"]).status.success());
+
+    // Reading does not create the data directory, and bad usage exits with 2.
+    assert!(!tdb(&["data/cli_missing", "keys"]).status.success());
+    assert!(fs::metadata("data/cli_missing").is_err());
+    assert_eq!(tdb(&[data_dir, "frobnicate"]).status.code(), Some(2));
+}